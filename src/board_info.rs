@@ -1,7 +1,9 @@
 use crate::cli::BoardInfoAttribute::{self, *};
+use crate::error::TpiError;
 use anyhow::bail;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{Buf, BufMut, BytesMut};
+use chrono::NaiveDate;
 use crc32fast::Hasher;
 use hex::FromHex;
 use std::time::Duration;
@@ -14,7 +16,15 @@ use std::{
 
 const BOARDINFO_SIZE: usize = 50;
 const HEADER_VER: u16 = 1u16;
+/// Location of the big-endian crc32, which covers all bytes following it.
+const CRC_FIELD: std::ops::Range<usize> = 2..6;
+/// The factory date is stored as the number of days since this date.
+const FACTORY_DATE_EPOCH: NaiveDate = match NaiveDate::from_ymd_opt(2023, 5, 1) {
+    Some(date) => date,
+    None => panic!("invalid factory date epoch"),
+};
 
+#[derive(Clone)]
 pub struct BoardInfo {
     _reserved: u16,
     crc32: u32,
@@ -33,17 +43,19 @@ impl BoardInfo {
         let mut file = OpenOptions::new().read(true).open(eeprom)?;
         let mut bytes = BytesMut::zeroed(BOARDINFO_SIZE);
         file.read_exact(bytes.as_mut())?;
+        self.verify_bytes(&bytes)
+    }
 
-        let mut hasher = Hasher::new();
-        hasher.update(&bytes[6..]);
-        let cksum = hasher.finalize();
+    /// Checks the crc32 of this board info against the checksum of `bytes`,
+    /// as read from the EEPROM.
+    fn verify_bytes(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        let cksum = checksum(bytes);
 
         if self.crc32 != cksum {
-            bail!(
+            bail!(TpiError::Integrity(format!(
                 "EEPROM checksum mismatch! read {:x}, expected {:x}",
-                self.crc32,
-                cksum
-            );
+                self.crc32, cksum
+            )));
         }
         Ok(())
     }
@@ -102,11 +114,24 @@ impl BoardInfo {
         self.hw_version = hw_version;
     }
 
-    /// days since May 1th 2024
+    /// days since May 1st 2023, see [`FACTORY_DATE_EPOCH`]
     pub fn factory_date(&mut self, days: u16) {
         self.factory_date = days;
     }
 
+    /// Sets the factory date from a calendar date, which can not lie before
+    /// [`FACTORY_DATE_EPOCH`].
+    pub fn factory_date_from(&mut self, date: NaiveDate) -> anyhow::Result<()> {
+        let days = (date - FACTORY_DATE_EPOCH).num_days();
+        let Ok(days) = u16::try_from(days) else {
+            bail!(TpiError::Usage(format!(
+                "factory date {date} is out of range, it is counted in days since {FACTORY_DATE_EPOCH}"
+            )));
+        };
+        self.factory_date = days;
+        Ok(())
+    }
+
     pub fn factory_serial(&mut self, serial: impl AsRef<str>) {
         let trimmed = serial.as_ref().as_bytes().take(16);
         let mut buffer = BytesMut::zeroed(16);
@@ -127,11 +152,9 @@ impl BoardInfo {
         Ok(())
     }
 
-    pub fn write_back(&mut self) -> io::Result<()> {
-        let eeprom = Self::find_i2c_device()?;
-        let mut file = OpenOptions::new().write(true).truncate(true).open(eeprom)?;
-        file.seek(io::SeekFrom::Start(0))?;
-
+    /// Serializes the board info into the EEPROM layout, including a freshly
+    /// calculated crc32.
+    pub fn to_bytes(&self) -> BytesMut {
         let mut bytes = BytesMut::with_capacity(BOARDINFO_SIZE);
         bytes.put_u16(self._reserved);
         bytes.put_u32(self.crc32);
@@ -143,11 +166,18 @@ impl BoardInfo {
         bytes.put_slice(&self.product_name);
         bytes.put_slice(&self.mac);
 
-        // calculate crc
-        let mut hasher = Hasher::new();
-        hasher.update(&bytes[6..]);
-        self.crc32 = hasher.finalize();
-        BigEndian::write_u32(&mut bytes.as_mut()[2..6], self.crc32);
+        let crc32 = checksum(&bytes);
+        BigEndian::write_u32(&mut bytes.as_mut()[CRC_FIELD], crc32);
+        bytes
+    }
+
+    pub fn write_back(&mut self) -> io::Result<()> {
+        let eeprom = Self::find_i2c_device()?;
+        let mut file = OpenOptions::new().write(true).truncate(true).open(eeprom)?;
+        file.seek(io::SeekFrom::Start(0))?;
+
+        let bytes = self.to_bytes();
+        self.crc32 = BigEndian::read_u32(&bytes[CRC_FIELD]);
 
         println!(
             "writing to eeprom:\n{:#?}",
@@ -164,26 +194,35 @@ impl BoardInfo {
 
     pub fn value_of(&self, attribute: &BoardInfoAttribute) -> String {
         match attribute {
-            BoardInfoAttribute::Reserved => format!("0x{:x}", self._reserved),
+            BoardInfoAttribute::Reserved => format!("0x{:04x}", self._reserved),
             BoardInfoAttribute::Crc32 => format!("0x{:04x}", self.crc32),
             BoardInfoAttribute::HdrVersion => self.hdr_version.to_string(),
             BoardInfoAttribute::HwVersion => parse_version_field(self.hw_version),
             BoardInfoAttribute::FactoryDate => {
-                let start_date = chrono::NaiveDate::from_ymd_opt(2023, 5, 1).expect("a valid date");
-                let date = start_date + chrono::Duration::days(self.factory_date as i64);
+                let date = FACTORY_DATE_EPOCH + chrono::Duration::days(self.factory_date as i64);
                 date.to_string()
             }
-            BoardInfoAttribute::FactorySerial => {
-                String::from_utf8_lossy(&self.factory_serial).to_string()
-            }
-            BoardInfoAttribute::ProductName => {
-                String::from_utf8_lossy(&self.product_name).to_string()
-            }
+            BoardInfoAttribute::FactorySerial => padded_string(&self.factory_serial),
+            BoardInfoAttribute::ProductName => padded_string(&self.product_name),
             BoardInfoAttribute::Mac => self.mac.iter().fold(String::new(), |mac, b| mac + &format!("{:02x}", b)),
         }
     }
 }
 
+/// Calculates the crc32 over the part of the EEPROM it protects.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(&bytes[CRC_FIELD.end..]);
+    hasher.finalize()
+}
+
+/// Returns the string stored in a fixed size field, without the zero bytes
+/// that pad it.
+fn padded_string(field: &[u8]) -> String {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).to_string()
+}
+
 /// Returns the semver version pointed to by `version_ptr` as a char*, prefixed
 /// with 'v'. e.g. v2.5.1
 ///
@@ -216,3 +255,106 @@ impl fmt::Debug for BoardInfo {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A board info as read from the EEPROM, with a valid crc32.
+    fn eeprom_bytes() -> BytesMut {
+        let mut info = BoardInfo::from_bytes(BytesMut::zeroed(BOARDINFO_SIZE)).unwrap();
+        info.product_name("Turing Pi 2");
+        info.to_bytes()
+    }
+
+    #[test]
+    fn value_of_formats_every_attribute() {
+        let mut bytes = BytesMut::with_capacity(BOARDINFO_SIZE);
+        bytes.put_u16(0x1234);
+        bytes.put_u32(0xdeadbeef);
+        bytes.put_u16_le(1);
+        bytes.put_u16_le((2 << 11) | (5 << 6) | 1);
+        bytes.put_u16_le(366);
+        bytes.put_slice(b"TP2-000123\0\0\0\0\0\0");
+        bytes.put_slice(b"Turing Pi 2\0\0\0\0\0");
+        bytes.put_slice(&[0x0a, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f]);
+        let info = BoardInfo::from_bytes(bytes).unwrap();
+
+        assert_eq!(info.value_of(&Reserved), "0x1234");
+        assert_eq!(info.value_of(&Crc32), "0xdeadbeef");
+        assert_eq!(info.value_of(&HdrVersion), "1");
+        assert_eq!(info.value_of(&HwVersion), "v2.5.1");
+        assert_eq!(info.value_of(&FactoryDate), "2024-05-01");
+        assert_eq!(info.value_of(&FactorySerial), "TP2-000123");
+        assert_eq!(info.value_of(&ProductName), "Turing Pi 2");
+        assert_eq!(info.value_of(&Mac), "0a1b2c3d4e5f");
+    }
+
+    #[test]
+    fn to_bytes_round_trips_every_field() {
+        let mut info = BoardInfo::from_bytes(eeprom_bytes()).unwrap();
+        info.hw_version(0x1042);
+        info.factory_date(123);
+        info.factory_serial("TP2-000123");
+        info.product_name("Turing Pi 2.5");
+        info.mac("0a1b2c3d4e5f").unwrap();
+
+        let bytes = info.to_bytes();
+        assert_eq!(bytes.len(), BOARDINFO_SIZE);
+
+        let parsed = BoardInfo::from_bytes(bytes.clone()).unwrap();
+        assert_eq!(parsed._reserved, 0);
+        assert_eq!(parsed.hdr_version, HEADER_VER);
+        assert_eq!(parsed.hw_version, 0x1042);
+        assert_eq!(parsed.factory_date, 123);
+        assert_eq!(parsed.value_of(&FactorySerial), "TP2-000123");
+        assert_eq!(parsed.value_of(&ProductName), "Turing Pi 2.5");
+        assert_eq!(parsed.mac, [0x0a, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f]);
+        assert_eq!(parsed.crc32, checksum(&bytes));
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn factory_date_counts_days_since_epoch() {
+        let mut info = BoardInfo::from_bytes(eeprom_bytes()).unwrap();
+        info.factory_date(366);
+        assert_eq!(info.value_of(&FactoryDate), "2024-05-01");
+
+        info.factory_date_from(NaiveDate::from_ymd_opt(2023, 6, 1).unwrap())
+            .unwrap();
+        assert_eq!(info.factory_date, 31);
+        assert_eq!(info.value_of(&FactoryDate), "2023-06-01");
+    }
+
+    #[test]
+    fn factory_date_out_of_range_is_rejected() {
+        let mut info = BoardInfo::from_bytes(eeprom_bytes()).unwrap();
+        let before_epoch = FACTORY_DATE_EPOCH.pred_opt().unwrap();
+        assert!(info.factory_date_from(before_epoch).is_err());
+
+        let last = FACTORY_DATE_EPOCH + chrono::Duration::days(u16::MAX.into());
+        info.factory_date_from(last).unwrap();
+        assert_eq!(info.factory_date, u16::MAX);
+        assert!(info.factory_date_from(last.succ_opt().unwrap()).is_err());
+    }
+
+    #[test]
+    fn written_bytes_pass_verification() {
+        let bytes = eeprom_bytes();
+        let info = BoardInfo::from_bytes(bytes.clone()).unwrap();
+        info.verify_bytes(&bytes).unwrap();
+    }
+
+    #[test]
+    fn corrupted_bytes_fail_verification() {
+        let mut bytes = eeprom_bytes();
+        let info = BoardInfo::from_bytes(bytes.clone()).unwrap();
+        bytes[BOARDINFO_SIZE - 1] ^= 0xff;
+
+        let err = info.verify_bytes(&bytes).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TpiError>(),
+            Some(TpiError::Integrity(_))
+        ));
+    }
+}
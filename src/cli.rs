@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::client::{UsbMode, DEFAULT_MAX_FRAME_SIZE, DEFAULT_TCP_KEEPALIVE};
+pub use crate::request::{ApiVersion, Scheme};
 use clap::{builder::NonEmptyStringValueParser, Args, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[cfg(not(feature = "localhost"))]
 const DEFAULT_HOST_NAME: &str = "turingpi.local";
@@ -27,12 +30,16 @@ const DEFAULT_HOST_NAME: &str = "127.0.0.1";
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true, arg_required_else_help = true)]
+#[command(
+    after_help = "Extra query parameters can be added to the request of a command with a trailing `-- key=value ...`\n\
+    Arguments can be read from a file with `@file`, which is replaced by the whitespace separated arguments in it"
+)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Commands>,
 
-    /// Specify the Turing-pi host to connect to. Note: IPv6 addresses must be wrapped in square
-    /// brackets e.g. `[::1]`
+    /// Specify the Turing-pi host to connect to. IPv6 addresses can be given with or without
+    /// square brackets e.g. `[::1]` or `::1`
     #[arg(default_value = DEFAULT_HOST_NAME, value_parser = NonEmptyStringValueParser::new(), long, global = true, env = "TPI_HOSTNAME")]
     pub host: Option<String>,
 
@@ -40,7 +47,8 @@ pub struct Cli {
     #[arg(long, global = true, env = "TPI_PORT")]
     pub port: Option<u16>,
 
-    /// Specify a user name to log in as. If unused, an interactive prompt will ask for credentials
+    /// Specify a user name to log in as. If unused, credentials are read from the netrc entry of
+    /// the host (`$NETRC` or `~/.netrc`), otherwise an interactive prompt will ask for them
     /// unless a cached token file is present.
     #[arg(long, global = true, env = "TPI_USERNAME")]
     pub user: Option<String>,
@@ -55,17 +63,171 @@ pub struct Cli {
     )]
     pub password: Option<String>,
 
+    /// Read the password from the first line of a file, e.g. a mounted CI secret. Takes
+    /// precedence over `--password`.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub password_file: Option<PathBuf>,
+
+    /// Read the password from the first line of stdin. Takes precedence over `--password`.
+    #[arg(long, global = true, conflicts_with = "password_file")]
+    pub password_stdin: bool,
+
+    /// File to cache the authentication token in. Defaults to a file in the user's cache
+    /// directory that is specific to the host, so that tokens of different BMCs do not
+    /// overwrite each other.
+    #[arg(long, global = true, env = "TPI_TOKEN_CACHE", value_name = "PATH")]
+    pub token_cache: Option<PathBuf>,
+
     /// Print results formatted as JSON
     #[arg(long, global = true, env = "TPI_OUTPUT_JSON")]
     pub json: bool,
 
+    /// Same as `--json`, but indents the JSON output for reading by eye.
+    #[arg(long, global = true)]
+    pub json_pretty: bool,
+
+    /// Hide progress bars and informational messages, e.g. when running from cron. Results,
+    /// warnings and errors are still printed.
+    #[arg(short, long, global = true, env = "TPI_QUIET")]
+    pub quiet: bool,
+
+    /// Run non-interactively: never prompt for credentials, never read or write the cached
+    /// token and give up quickly on unreachable hosts. This is the recommended profile for
+    /// scripts and CI. Credentials must be supplied with `--user` and `--password` (or
+    /// `--password-file`, `--password-stdin`), or netrc.
+    #[arg(long, global = true, env = "TPI_BATCH")]
+    pub batch: bool,
+
+    /// Print a breakdown of the time spent in each phase of the command to stderr, e.g.
+    /// authentication, requests and, for flashing, upload, write and verification.
+    #[arg(long, global = true)]
+    pub profile: bool,
+
+    /// Select how progress bars are drawn. `ascii` avoids unicode characters
+    /// that render poorly on some (serial) terminals.
+    #[arg(
+        long,
+        global = true,
+        default_value = "full",
+        env = "TPI_PROGRESS_STYLE"
+    )]
+    pub progress_style: ProgressBarStyle,
+
+    /// Randomize the delay between two polls of the BMC by up to this fraction in either
+    /// direction, e.g. `0.2` for ±20%. Keeps several clients from polling in lockstep.
+    #[arg(long, global = true, default_value_t = 0.0, value_name = "FRACTION")]
+    #[arg(value_parser = parse_fraction)]
+    pub interval_jitter: f64,
+
+    /// Node to use when a command requires `--node` but it was omitted. An explicit `--node`
+    /// always takes precedence. Commands that address all nodes when `--node` is omitted,
+    /// such as `power on` or `uart get`, ignore this default.
+    #[arg(long, global = true, env = "TPI_NODE", value_name = "NODE")]
+    #[arg(value_parser = clap::value_parser!(u8).range(0..5))]
+    pub default_node: Option<u8>,
+
+    /// Number nodes from 0 or from 1. Applies to all node numbers tpi reads, e.g. `--node`, and
+    /// prints.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1,
+        env = "TPI_NODE_BASE",
+        value_name = "BASE"
+    )]
+    #[arg(value_parser = clap::value_parser!(u8).range(0..=1))]
+    pub node_base: u8,
+
+    /// Seconds between TCP keep-alive probes on connections to the BMC; `0` disables them.
+    /// Verifying a flashed image takes minutes in which little is sent, long enough for NAT
+    /// gateways and firewalls to drop an idle connection, which the progress poll then runs
+    /// into.
+    #[arg(long, global = true, value_name = "SECS")]
+    #[arg(default_value_t = DEFAULT_TCP_KEEPALIVE.as_secs())]
+    pub tcp_keepalive: u64,
+
+    /// Seconds to wait for the BMC to respond before giving up; `0` waits indefinitely.
+    /// Defaults to 5 for `healthcheck`, which then reports the BMC as unhealthy, and to 30
+    /// for other commands. Does not apply to `flash` and `firmware`, whose uploads take
+    /// minutes.
+    #[arg(long, global = true, env = "TPI_TIMEOUT", value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    /// How often to retry a query when the BMC is unreachable or answers with 502 or 503, as
+    /// it does right after a reboot. Waits 500ms before the first retry, twice as long before
+    /// each further one, up to 10s, or as long as a 503 reply asks for with `Retry-After`,
+    /// again up to 10s. Requests that change a setting are never retried.
+    #[arg(long, global = true, default_value_t = 3, value_name = "COUNT")]
+    #[arg(value_parser = clap::value_parser!(u32).range(0..=10))]
+    pub retries: u32,
+
     /// Force which version of the BMC API to use. Try lower the version if you are running
     /// older BMC firmware.
     #[arg(default_value = "v1-1", short, global = true)]
     pub api_version: Option<ApiVersion>,
 
-    #[arg(short, name = "gen completion", exclusive = true)]
+    /// Connect with this scheme instead of the one of the API version, `http` for v1 and
+    /// `https` for v1-1. For a BMC behind a reverse proxy that terminates TLS, for example.
+    #[arg(long, global = true)]
+    pub scheme: Option<Scheme>,
+
+    /// Print the completion script for the given shell.
+    #[arg(short, name = "gen completion")]
     pub gencompletion: Option<clap_complete::shells::Shell>,
+
+    /// Write the completion script of `-g` into this directory, under the file name the shell
+    /// expects, e.g. `_tpi` for zsh, instead of printing it.
+    #[arg(long, value_name = "DIR", requires = "gen completion")]
+    pub out_dir: Option<PathBuf>,
+
+    /// Extra query parameters for the request of the command, passed as
+    /// `key=value` after a trailing `--`. Filled in by `main`.
+    #[arg(skip)]
+    pub query_params: Vec<(String, String)>,
+}
+
+impl Cli {
+    /// Converts the node numbers given on the command line from `--node-base` numbering
+    /// to the 1-based numbering used internally.
+    pub fn normalize_nodes(&mut self) -> Result<(), String> {
+        let base = self.node_base;
+        let normalize = |n: &mut u8| {
+            *n = node_from_base(*n, base).ok_or_else(|| {
+                format!(
+                    "node {n} does not exist, nodes are numbered {}-{}",
+                    node_to_base(1, base),
+                    node_to_base(4, base)
+                )
+            })?;
+            Ok(())
+        };
+
+        self.default_node.iter_mut().try_for_each(normalize)?;
+        match &mut self.command {
+            Some(Commands::Power(args)) => args.node.iter_mut().try_for_each(normalize),
+            Some(Commands::Usb(args)) => args.node.iter_mut().try_for_each(normalize),
+            Some(Commands::Flash(args)) => args.node.iter_mut().try_for_each(normalize),
+            Some(Commands::Uart(args)) => args.node.iter_mut().try_for_each(normalize),
+            Some(Commands::Advanced(args)) => args.node.iter_mut().try_for_each(normalize),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Converts a node number in the given `--node-base` numbering to the 1-based
+/// numbering, `None` if there is no such node.
+pub fn node_from_base(node: u8, base: u8) -> Option<u8> {
+    (node + 1).checked_sub(base).filter(|n| (1..=4).contains(n))
+}
+
+/// Converts a 1-based node number to the given `--node-base` numbering.
+pub fn node_to_base(node: u8, base: u8) -> u8 {
+    node + base - 1
+}
+
+/// Converts a 1-based node number to the 0-based number the API expects.
+pub fn api_node(node: u8) -> String {
+    (node - 1).to_string()
 }
 
 #[derive(Subcommand)]
@@ -108,10 +270,25 @@ pub enum Commands {
     Eeprom(EepromArgs),
 
     /// Print turing-pi info
-    Info,
+    Info(InfoArgs),
+
+    /// Check whether the BMC responds. Exits with 0 when healthy and 1 otherwise.
+    Healthcheck,
+
+    /// Query every read-only endpoint of the BMC and report which ones work with
+    /// this version of tpi. Never changes any setting.
+    SelfTest,
+
+    /// Authenticate with the BMC. The token is cached for subsequent commands unless
+    /// credentials are passed on the command line.
+    Login(LoginArgs),
 
     /// Reboot the BMC chip. Nodes will lose power until booted!
     Reboot,
+
+    /// Send an arbitrary request to the BMC and print the raw JSON response.
+    /// Intended for endpoints that tpi does not support yet.
+    Raw(RawArgs),
 }
 
 #[derive(ValueEnum, Clone, PartialEq, Eq)]
@@ -143,12 +320,28 @@ pub enum UsbCmd {
     Status,
 }
 
+impl UsbCmd {
+    /// The mode to set, `None` for `status`.
+    pub fn usb_mode(&self) -> Option<UsbMode> {
+        match self {
+            UsbCmd::Device => Some(UsbMode::Device),
+            UsbCmd::Host => Some(UsbMode::Host),
+            UsbCmd::Flash => Some(UsbMode::Flash),
+            UsbCmd::Status => None,
+        }
+    }
+}
+
 #[derive(ValueEnum, Clone, PartialEq, Eq)]
 pub enum PowerCmd {
     On,
     Off,
     Reset,
     Status,
+    /// Run the power steps listed in a file, see `FILE`
+    Sequence,
+    /// Power off, wait for `--delay`, then power on again
+    Cycle,
 }
 
 #[derive(ValueEnum, Clone, PartialEq, Eq)]
@@ -157,29 +350,32 @@ pub enum EthCmd {
 }
 
 #[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
-pub enum ApiVersion {
-    V1,
-    V1_1,
-}
-
-impl ApiVersion {
-    pub fn scheme(&self) -> &str {
-        match self {
-            ApiVersion::V1 => "http",
-            ApiVersion::V1_1 => "https",
-        }
-    }
+pub enum ProgressBarStyle {
+    /// Colored bar with elapsed time, transferred bytes and ETA
+    Full,
+    /// Bar with percentage only
+    Minimal,
+    /// Same as full, without colors and unicode characters
+    Ascii,
 }
 
 #[derive(ValueEnum, Clone, PartialEq, Eq)]
 pub enum BoardInfoAttribute {
+    /// Raw value of the first two bytes. Not used by any known firmware, and
+    /// therefore read-only.
     Reserved,
+    /// Checksum over all fields following it
     Crc32,
+    /// Version of the layout of the EEPROM
     HdrVersion,
+    /// Hardware revision of the board, e.g. v2.5.0
     HwVersion,
+    /// Production date of the board
     FactoryDate,
+    /// Serial number of the board
     FactorySerial,
     ProductName,
+    /// MAC address assigned to the board
     Mac,
 }
 
@@ -188,32 +384,53 @@ pub struct EepromArgs {
     /// Specify command
     pub cmd: GetSet,
     pub attribute: Option<BoardInfoAttribute>,
+    /// Only for `set`: print the changes that would be written, without
+    /// writing the EEPROM.
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
 #[derive(Args, Clone)]
 pub struct EthArgs {
     /// Specify command
     pub cmd: EthCmd,
+    /// Do not ask for confirmation before resetting the switch
+    #[arg(short, long)]
+    pub yes: bool,
 }
 
 #[derive(Args)]
 pub struct AdvancedArgs {
     pub mode: ModeCmd,
-    /// [possible values: 1-4]
+    /// [possible values: 1-4, or 0-3 with `--node-base 0`]
     #[arg(short, long)]
-    #[arg(value_parser = clap::value_parser!(u8).range(1..5))]
-    pub node: u8,
+    #[arg(value_parser = clap::value_parser!(u8).range(0..5))]
+    pub node: Option<u8>,
 }
 
 #[derive(Args)]
 pub struct UartArgs {
     pub action: GetSet,
-    /// [possible values: 1-4], Not specifying a node selects all nodes.
+    /// [possible values: 1-4, or 0-3 with `--node-base 0`], Not specifying a node selects
+    /// all nodes.
     #[arg(short, long)]
-    #[arg(value_parser = clap::value_parser!(u8).range(1..5))]
-    pub node: u8,
+    #[arg(value_parser = clap::value_parser!(u8).range(0..5))]
+    pub node: Option<u8>,
     #[arg(short, long)]
     pub cmd: Option<String>,
+    /// Only for `set`: send the contents of a file instead of `--cmd`, newlines included.
+    /// `-` reads from stdin, e.g. a heredoc. Large files are sent in several requests.
+    #[arg(long, value_name = "PATH", conflicts_with = "cmd")]
+    pub cmd_file: Option<PathBuf>,
+    /// Only for `get`: keep polling the node's UART and print new output as it
+    /// arrives, like `tail -f`, until Ctrl-C. Follows `--node`, or the default
+    /// node if omitted.
+    #[arg(short, long)]
+    pub follow: bool,
+    /// Milliseconds between two polls of `--follow`.
+    #[arg(long, default_value_t = 250, value_name = "MS", requires = "follow")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
 }
 
 #[derive(Args)]
@@ -223,9 +440,9 @@ pub struct UsbArgs {
     /// instead of USB-A, route the USB-bus to the BMC chip.
     #[arg(short, long)]
     pub bmc: bool,
-    /// [possible values: 1-4]
+    /// [possible values: 1-4, or 0-3 with `--node-base 0`]
     #[arg(short, long)]
-    #[arg(value_parser = clap::value_parser!(u8).range(1..5))]
+    #[arg(value_parser = clap::value_parser!(u8).range(0..5))]
     pub node: Option<u8>,
 }
 
@@ -234,9 +451,21 @@ pub struct FirmwareArgs {
     #[arg(short, long)]
     pub file: PathBuf,
     /// A sha256 checksum will be used by the BMC to verify the integrity
-    /// of the input, in this case, the received OS image.
-    #[arg(long)]
+    /// of the input, in this case, the received OS image. `auto` computes it
+    /// from the file before uploading.
+    #[arg(long, value_name = "HEX|auto")]
     pub sha256: Option<String>,
+    /// Milliseconds to wait before the progress is polled for the first time.
+    #[arg(long, default_value_t = 3000, value_name = "MS")]
+    pub progress_delay: u64,
+    /// Milliseconds between two consecutive progress polls.
+    #[arg(long, default_value_t = 500, value_name = "MS")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    pub progress_interval: u64,
+    /// Compress the upload with gzip, if the BMC advertises support for it.
+    /// Saves bandwidth on slow links for compressible images.
+    #[arg(long)]
+    pub compress: bool,
 }
 
 #[derive(Args, Clone)]
@@ -246,16 +475,29 @@ pub struct FlashArgs {
     /// typically a BMC-visible microSD card.
     #[arg(short, long)]
     pub local: bool,
-    /// Update a node with the given image.
-    #[arg(short, long)]
-    pub image_path: PathBuf,
-    /// [possible values: 1-4]
-    #[arg(short, long)]
-    #[arg(value_parser = clap::value_parser!(u8).range(1..5))]
-    pub node: u8,
+    /// Update a node with the given image. This can also be a block device,
+    /// e.g. a cloned microSD card, or an `http(s)://` URL, which is streamed
+    /// to the BMC without being stored locally.
+    #[arg(short, long, required_unless_present = "image_dir")]
+    pub image_path: Option<PathBuf>,
+    /// Flash every image in the given directory to the node named by its
+    /// file name, e.g. `node1.img` is flashed to node 1. Nodes are flashed
+    /// one after another.
+    #[arg(long, conflicts_with_all = ["image_path", "node", "local", "sha256"])]
+    pub image_dir: Option<PathBuf>,
+    /// [possible values: 1-4, or 0-3 with `--node-base 0`] Several nodes can be
+    /// given separated by commas, e.g. `1,3`; the image is then flashed to one
+    /// after another.
+    #[arg(short, long, value_delimiter = ',')]
+    #[arg(value_parser = clap::value_parser!(u8).range(0..5))]
+    pub node: Vec<u8>,
+    /// Flash the image to all nodes, one after another.
+    #[arg(long, conflicts_with_all = ["node", "image_dir"])]
+    pub all: bool,
     /// A sha256 checksum will be used by the BMC to verify the integrity
-    /// of the input, in this case, the received OS image.
-    #[arg(long)]
+    /// of the input, in this case, the received OS image. `auto` computes it
+    /// from the file before uploading.
+    #[arg(long, value_name = "HEX|auto")]
     pub sha256: Option<String>,
     /// Opt out of the crc integrity check. This is check is not responsible for
     /// the sha256 validation. But validates the written areas on the node with
@@ -263,30 +505,243 @@ pub struct FlashArgs {
     /// but permits corrupted written data.
     #[arg(long)]
     pub skip_crc: bool,
+    /// Do not ask for confirmation when `--skip-crc` is used
+    #[arg(short, long, requires = "skip_crc")]
+    pub yes: bool,
+    /// Print the sha256 digest of the uploaded image after flashing, as a
+    /// record of exactly which bytes were sent to the BMC.
+    #[arg(long, conflicts_with_all = ["sha256", "local"])]
+    pub print_digest: bool,
+    /// Do not warn about images that are too small to be an OS image.
+    #[arg(long)]
+    pub force: bool,
+    /// Compress the upload with gzip, if the BMC advertises support for it.
+    /// Saves bandwidth on slow links for compressible images.
+    #[arg(long, conflicts_with = "local")]
+    pub compress: bool,
+    /// Power the node off before flashing, keeping it unpowered while it is
+    /// being written.
+    #[arg(long, conflicts_with = "image_dir")]
+    pub power_off_during: bool,
+    /// Power the node on once flashing completed successfully.
+    #[arg(long, requires = "power_off_during")]
+    pub power_on_after: bool,
+    /// Fail, instead of warn, when the BMC recorded a different number of
+    /// bytes than were uploaded.
+    #[arg(long, conflicts_with = "local")]
+    pub strict_length: bool,
+    /// Check the image, node and checksum, and print the request that would
+    /// start flashing, without sending anything.
+    #[arg(long, conflicts_with = "local")]
+    pub dry_run: bool,
+    /// Read the flashed storage back from the node and compare it against
+    /// the image. Requires a BMC with a read-back endpoint.
+    #[arg(long, conflicts_with = "dry_run")]
+    pub verify_after: bool,
+    /// Largest chunk, in bytes, written to the connection at once while
+    /// uploading the image. Independent of the buffer the image is read
+    /// into. Larger chunks can speed up transfers on networks with jumbo
+    /// frames. Only applies to API version v1-1.
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_SIZE as u64, value_name = "BYTES")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1024..=16 * 1024 * 1024))]
+    pub max_frame_size: u64,
+    /// Flash a specific storage device of the node, e.g. `/dev/nvme0n1`. Defaults to the
+    /// eMMC. Support for this option depends on the BMC firmware.
+    #[arg(long, value_parser = NonEmptyStringValueParser::new())]
+    pub target: Option<String>,
+    /// Milliseconds to wait before the progress is polled for the first time.
+    #[arg(long, default_value_t = 3000, value_name = "MS")]
+    pub progress_delay: u64,
+    /// Milliseconds between two consecutive progress polls.
+    #[arg(long, default_value_t = 500, value_name = "MS")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    pub progress_interval: u64,
+}
+
+#[derive(Args)]
+pub struct RawArgs {
+    /// Value of the `opt` parameter
+    #[arg(long)]
+    pub op: GetSet,
+    /// Value of the `type` parameter
+    #[arg(long = "type", value_parser = NonEmptyStringValueParser::new())]
+    pub kind: String,
+    /// Additional parameter in the form key=value, can be repeated
+    #[arg(short, long = "param", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub params: Vec<(String, String)>,
+    /// Do not ask for confirmation before sending a `set` request
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+fn parse_fraction(input: &str) -> Result<f64, String> {
+    match input.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("expected a number between 0 and 1, got `{input}`")),
+    }
+}
+
+/// Parses a delay such as `5s` or `500ms`. A bare number is in milliseconds.
+pub fn parse_delay(delay: &str) -> Option<Duration> {
+    if let Some(ms) = delay.strip_suffix("ms") {
+        ms.parse().ok().map(Duration::from_millis)
+    } else if let Some(secs) = delay.strip_suffix('s') {
+        secs.parse()
+            .ok()
+            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+    } else {
+        delay.parse().ok().map(Duration::from_millis)
+    }
+}
+
+/// Parses a duration argument. Unlike [`parse_delay`], the unit is required,
+/// so that `--period 10` is not silently taken as 10 milliseconds.
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    input
+        .ends_with('s')
+        .then(|| parse_delay(input))
+        .flatten()
+        .ok_or_else(|| format!("expected a duration such as `30s` or `500ms`, got `{input}`"))
+}
+
+pub fn parse_key_value(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{input}`")),
+    }
+}
+
+#[derive(Args)]
+pub struct InfoArgs {
+    /// Print only the value of this field, e.g. `version` or `ip`, without the
+    /// table around it.
+    pub field: Option<String>,
+}
+
+#[derive(Args)]
+pub struct LoginArgs {
+    /// Print the bearer token to stdout, and nothing else. Warning: this exposes a secret,
+    /// make sure the output does not end up in logs.
+    #[arg(long)]
+    pub print_token: bool,
 }
 
 #[derive(Args)]
 pub struct PowerArgs {
     /// Specify command
     pub cmd: PowerCmd,
-    /// [possible values: 1-4], Not specifying a node selects all nodes.
+    /// [possible values: 1-4, or 0-3 with `--node-base 0`]
     #[arg(short, long)]
-    #[arg(value_parser = clap::value_parser!(u8).range(1..5))]
+    #[arg(value_parser = clap::value_parser!(u8).range(0..5))]
     pub node: Option<u8>,
+    /// Power all nodes on or off. Without `--node` or `--all`, confirmation is
+    /// asked before affecting all nodes.
+    #[arg(long, conflicts_with = "node")]
+    pub all: bool,
+    /// Only for `reset`: keep the node powered off for the given number of
+    /// milliseconds before powering it on again. [max: 60000]
+    #[arg(long, value_name = "MS")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..=60_000))]
+    pub hold: Option<u64>,
+    /// Only for `cycle`: how long the node stays powered off, e.g. `3s` or
+    /// `500ms`. [default: 3s]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub delay: Option<Duration>,
+    /// Only for `on` without `--node`: power the nodes on one at a time, the
+    /// given number of milliseconds apart, instead of all at once. Limits the
+    /// inrush current on marginal power supplies. [max: 60000]
+    #[arg(long, value_name = "MS", conflicts_with = "node")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..=60_000))]
+    pub stagger: Option<u64>,
+    /// Only for `sequence`: file with one step per line in the form
+    /// `<on|off|reset> <node> [delay]`, e.g. `on 1 5s`. The optional delay,
+    /// in `s` or `ms`, is waited after the step. Lines starting with `#` are
+    /// ignored.
+    #[arg(required_if_eq("cmd", "sequence"))]
+    #[arg(conflicts_with_all = ["node", "all", "hold", "wait_ssh"])]
+    pub file: Option<PathBuf>,
+    /// Only for `status`: exit with 0 when the node given by `--node` is
+    /// powered on, and with 3 when it is off. Implied by `--node`, this flag
+    /// makes `status` consider `TPI_NODE` as well.
+    #[arg(long, conflicts_with = "all")]
+    pub exit_code: bool,
+    /// Only for `on`: after powering on, wait until a TCP connection to the
+    /// given address succeeds, e.g. the SSH server of the node. The port
+    /// defaults to 22.
+    #[arg(long, visible_alias = "wait-tcp", value_name = "HOST[:PORT]")]
+    #[arg(value_parser = NonEmptyStringValueParser::new())]
+    pub wait_ssh: Option<String>,
+    /// Seconds to wait for `--wait-ssh` before giving up. [default: 300]
+    #[arg(long, value_name = "SECS", requires = "wait_ssh")]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    pub wait_timeout: Option<u64>,
 }
 
 #[derive(Args, Clone)]
 pub struct CoolingArgs {
     /// Specify command
     pub cmd: CoolingCmd,
-    /// Specify the cooling device (required for set command)
+    /// Specify the cooling device (required for set command). `curve` controls
+    /// every cooling device when it is omitted.
     pub device: Option<String>,
     /// Specify the cooling device speed (required for set command)
     pub speed: Option<u32>,
+    /// Set every cooling device to the speed given by `--speed`, e.g.
+    /// `tpi cooling set --all --speed 3`.
+    #[arg(long, requires = "all_speed", conflicts_with = "device")]
+    pub all: bool,
+    /// Speed of every cooling device, with `--all`.
+    #[arg(
+        long = "speed",
+        value_name = "SPEED",
+        requires = "all",
+        conflicts_with = "device"
+    )]
+    pub all_speed: Option<u32>,
+    /// Do not check that the device is listed by the BMC before setting its
+    /// speed, for firmware that does not list its cooling devices.
+    #[arg(long)]
+    pub skip_device_check: bool,
+    /// Set the speed even if it exceeds the `max_speed` the BMC reports for
+    /// the device.
+    #[arg(long)]
+    pub force: bool,
+    /// Points of the fan curve of `curve`, as `TEMP:SPEED` with the temperature in °C,
+    /// e.g. `--points 40:0,60:2,75:4`. The speed is interpolated linearly between
+    /// points and held beyond the first and last one.
+    #[arg(long, value_name = "TEMP:SPEED", value_delimiter = ',')]
+    #[arg(value_parser = parse_curve_point)]
+    pub points: Vec<CurvePoint>,
+    /// How often `curve` reads the temperature and adjusts the fan speed.
+    #[arg(long, default_value = "10s", value_name = "DURATION")]
+    #[arg(value_parser = parse_duration)]
+    pub period: Duration,
 }
 
 #[derive(ValueEnum, Clone, PartialEq, Eq)]
 pub enum CoolingCmd {
     Set,
     Status,
+    /// Print the names of the available cooling devices, one per line
+    List,
+    /// Set the fan speed from the temperature of the board, following the
+    /// curve given by `--points`, until interrupted with Ctrl-C
+    Curve,
+}
+
+/// Point of a fan curve, see `cooling curve --points`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CurvePoint {
+    /// Temperature in °C.
+    pub temp: f64,
+    pub speed: u32,
+}
+
+fn parse_curve_point(input: &str) -> Result<CurvePoint, String> {
+    let point = input.split_once(':').and_then(|(temp, speed)| {
+        let temp = temp.trim().parse::<f64>().ok().filter(|t| t.is_finite())?;
+        let speed = speed.trim().parse().ok()?;
+        Some(CurvePoint { temp, speed })
+    });
+    point.ok_or_else(|| format!("expected TEMP:SPEED such as `60:2`, got `{input}`"))
 }
@@ -0,0 +1,675 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Programmatic access to the BMC for tools that use tpi as a library.
+
+use std::str::from_utf8;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use async_compression::tokio::bufread::GzipEncoder;
+use platform_info::{PlatformInfo, PlatformInfoAPI, UNameAPI};
+use reqwest::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Body, Client, ClientBuilder};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio_util::io::ReaderStream;
+
+use crate::cli::api_node;
+use crate::error::TpiError;
+use crate::profile::{Profiler, ProfilingResolver};
+use crate::progress::{jittered, FlashEvent, FlashWatcher};
+use crate::request::{ApiVersion, Request, Scheme};
+use crate::response::{
+    parse_response, parse_result, parse_result_object, FlashHandle, Info, PowerStatus,
+};
+
+/// Upper bound for establishing a connection to the BMC in batch mode.
+const BATCH_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Size of the buffer a compressed upload is read through.
+const UPLOAD_BUFFER_SIZE: usize = 1024 * 32;
+
+/// Default largest chunk written to the connection at once while uploading,
+/// see `--max-frame-size`.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 32;
+
+/// Default interval of TCP keep-alive probes, see `--tcp-keepalive`.
+pub const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Creates the HTTP client used to talk to the BMC with the given API version
+/// over `scheme`. `timeout` bounds every request, including its body. Name
+/// resolution is timed by `profiler`, if given.
+pub fn create_http_client(
+    version: ApiVersion,
+    scheme: Scheme,
+    batch: bool,
+    tcp_keepalive: Option<Duration>,
+    timeout: Option<Duration>,
+    profiler: Option<&Profiler>,
+) -> Result<Client> {
+    let mut builder = ClientBuilder::new().tcp_keepalive(tcp_keepalive);
+    if let Some(profiler) = profiler {
+        builder = builder.dns_resolver(Arc::new(ProfilingResolver(profiler.clone())));
+    }
+    if batch {
+        builder = builder.connect_timeout(BATCH_CONNECT_TIMEOUT);
+    }
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+
+    if scheme == Scheme::Https {
+        // The BMC comes with a self-signed certificate.
+        builder = builder.danger_accept_invalid_certs(true).https_only(true);
+    }
+
+    if version == ApiVersion::V1 {
+        return Ok(builder.build()?);
+    }
+
+    Ok(builder.gzip(true).http1_only().build()?)
+}
+
+/// Returns the user agent, e.g. `TPI/1.0.7 (Linux;x86_64;GNU/Linux)`. The
+/// platform part is left out when it cannot be determined completely.
+pub fn user_agent() -> String {
+    let product = format!("TPI/{}", env!("CARGO_PKG_VERSION"));
+    let Ok(nfo) = PlatformInfo::new() else {
+        return product;
+    };
+
+    let platform = [nfo.sysname(), nfo.machine(), nfo.osname()].map(|s| s.to_string_lossy());
+    if platform.iter().any(|s| s.trim().is_empty()) {
+        return product;
+    }
+
+    format!("{product} ({})", platform.join(";"))
+}
+
+/// Sends `request` and returns the first element of the `response` array
+/// of the reply, or the reply itself if it is a bare string.
+pub async fn query(client: &Client, request: Request) -> Result<serde_json::Value> {
+    let response = request.send(client.clone()).await?;
+    let status = response.status();
+    let bytes = response.bytes().await?;
+
+    let body: serde_json::Value = serde_json::from_slice(&bytes).map_err(|_| {
+        TpiError::Bmc(format!(
+            "{}:\n{}",
+            status.canonical_reason().unwrap_or("unknown reason"),
+            from_utf8(&bytes).unwrap_or("error parsing server response")
+        ))
+    })?;
+
+    // Some replies are a bare string instead of a `response` object, an
+    // error message unless the status says otherwise.
+    if let Some(message) = body.as_str() {
+        ensure!(status.is_success(), TpiError::Bmc(message.to_string()));
+        return Ok(body);
+    }
+    ensure!(
+        status.is_success(),
+        TpiError::Bmc(format!("{status}: {body}"))
+    );
+
+    body.get("response")
+        .and_then(|r| r.get(0))
+        .cloned()
+        .context("expected 'response' key in JSON payload")
+}
+
+/// Role of a node on the USB bus, see [`BmcClient::set_usb_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbMode {
+    /// The node is the USB host.
+    Host,
+    /// The node is a USB device.
+    Device,
+    /// The node is a USB device in flashing mode, e.g. to be flashed over its
+    /// USB_OTG port.
+    Flash,
+}
+
+/// What to flash where, see [`BmcClient::start_flash`].
+#[derive(Debug, Clone, Default)]
+pub struct FlashParams {
+    /// Node 1 to 4.
+    pub node: u8,
+    /// Name of the image, as the BMC reports it.
+    pub file_name: String,
+    /// Size of the image in bytes.
+    pub size: u64,
+    /// Checksum the BMC verifies the written image against.
+    pub sha256: Option<String>,
+    /// Skip the verification of the written image.
+    pub skip_crc: bool,
+    /// Device of the node to write to, instead of its default storage.
+    pub device: Option<String>,
+}
+
+impl FlashParams {
+    /// Adds the parameters that start this flash to `request`.
+    pub fn append_to(&self, request: &mut Request) {
+        let mut serializer = request.url_mut().query_pairs_mut();
+        serializer
+            .append_pair("opt", "set")
+            .append_pair("type", "flash")
+            .append_pair("file", &self.file_name)
+            .append_pair("length", &self.size.to_string())
+            .append_pair("node", &api_node(self.node));
+
+        if let Some(sha256) = &self.sha256 {
+            serializer.append_pair("sha256", sha256);
+        }
+
+        if self.skip_crc {
+            serializer.append_key_only("skip_crc");
+        }
+
+        if let Some(device) = &self.device {
+            serializer.append_pair("device", device);
+        }
+    }
+}
+
+/// A transfer the BMC waits for the data of, see [`BmcClient::upload`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    /// Identifies the transfer, also in the progress of the flash.
+    pub handle: u64,
+    /// Whether the BMC accepts a compressed upload, see
+    /// [`BmcClient::upload_gzip`].
+    pub accepts_gzip: bool,
+}
+
+/// Client for a single BMC. It never prompts: requests that need
+/// authentication fail unless credentials were supplied.
+#[derive(Clone)]
+pub struct BmcClient {
+    request: Request,
+    client: Client,
+    interval_jitter: f64,
+}
+
+impl BmcClient {
+    /// `host` may include a port, e.g. `turingpi.local:8080`.
+    pub fn new(
+        host: impl Into<String>,
+        version: ApiVersion,
+        user: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Result<Self> {
+        let creds = (Some(user.into()), Some(password.into()));
+        let request = Request::new(host.into(), version, creds, true, &user_agent())?;
+        let client = create_http_client(
+            version,
+            version.scheme(),
+            true,
+            Some(DEFAULT_TCP_KEEPALIVE),
+            None,
+            None,
+        )?;
+        Ok(Self {
+            request,
+            client,
+            interval_jitter: 0.0,
+        })
+    }
+
+    /// Client that sends its requests like `request`, with its address,
+    /// credentials and retries, over `client`. Unlike [`BmcClient::new`], it
+    /// prompts for credentials if `request` was not created in batch mode.
+    pub fn with_request(request: Request, client: Client) -> Self {
+        Self {
+            request,
+            client,
+            interval_jitter: 0.0,
+        }
+    }
+
+    /// Randomizes the interval between two polls of the BMC by up to
+    /// `fraction` in either direction, see [`jittered`].
+    pub fn set_interval_jitter(&mut self, fraction: f64) {
+        self.interval_jitter = fraction;
+    }
+
+    /// Returns a client that adds `params` to every query, e.g. parameters of
+    /// newer firmware that this client has no method for.
+    pub fn with_query_params(&self, params: &[(String, String)]) -> Self {
+        let mut request = self.request.clone();
+        request.url_mut().query_pairs_mut().extend_pairs(params);
+        Self {
+            request,
+            client: self.client.clone(),
+            interval_jitter: self.interval_jitter,
+        }
+    }
+
+    async fn get(&self, kind: &str) -> Result<serde_json::Value> {
+        self.send("get", kind, &[]).await
+    }
+
+    async fn set(&self, kind: &str, params: &[(&str, String)]) -> Result<()> {
+        let response = self.send("set", kind, params).await?;
+        let result: String = parse_result(&response)?;
+        ensure!(
+            result.eq_ignore_ascii_case("ok"),
+            TpiError::Bmc(format!("`{kind}` was not applied: {response}"))
+        );
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        opt: &str,
+        kind: &str,
+        params: &[(&str, String)],
+    ) -> Result<serde_json::Value> {
+        let mut request = self.request.clone();
+        request
+            .url_mut()
+            .query_pairs_mut()
+            .append_pair("opt", opt)
+            .append_pair("type", kind)
+            .extend_pairs(params);
+        query(&self.client, request).await
+    }
+
+    /// Flashes node `params.node` with `image`, which must be `params.size`
+    /// bytes long, and follows the flash until it completes, see
+    /// [`BmcClient::watch_flash`]. Returns the number of bytes the BMC wrote,
+    /// if it reported it. Requires API version v1-1.
+    pub async fn flash(
+        &self,
+        params: &FlashParams,
+        image: impl AsyncRead + Unpin + Send + Sync + 'static,
+        interval: Duration,
+        on_event: impl FnMut(&FlashEvent),
+    ) -> Result<Option<u64>> {
+        let transfer = self.start_flash(params).await?;
+        self.upload(&transfer, image, params.size, DEFAULT_MAX_FRAME_SIZE)
+            .await?;
+        self.watch_flash(transfer.handle, interval, on_event).await
+    }
+
+    /// Asks the BMC to flash node `params.node`. The image is then sent with
+    /// [`BmcClient::upload`]. Requires API version v1-1.
+    pub async fn start_flash(&self, params: &FlashParams) -> Result<Transfer> {
+        ensure_node(params.node)?;
+        let mut request = self.request.clone();
+        params.append_to(&mut request);
+        self.start_transfer(request).await
+    }
+
+    /// Asks the BMC to upgrade its firmware with the image `file_name` of
+    /// `size` bytes, verified against `sha256` if given. The image is then
+    /// sent with [`BmcClient::upload`]. Requires API version v1-1.
+    pub async fn start_firmware_upgrade(
+        &self,
+        file_name: &str,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Result<Transfer> {
+        let mut request = self.request.clone();
+        let mut serializer = request.url_mut().query_pairs_mut();
+        serializer
+            .append_pair("opt", "set")
+            .append_pair("type", "firmware")
+            .append_pair("file", file_name)
+            .append_pair("length", &size.to_string());
+        if let Some(sha256) = sha256 {
+            serializer.append_pair("sha256", sha256);
+        }
+        drop(serializer);
+        self.start_transfer(request).await
+    }
+
+    async fn start_transfer(&self, request: Request) -> Result<Transfer> {
+        ensure!(
+            request.version() == ApiVersion::V1_1,
+            TpiError::Usage("uploading an image requires API version v1-1".into())
+        );
+        let response = request
+            .send(self.client.clone())
+            .await
+            .context("flash request")?;
+
+        if !response.status().is_success() {
+            bail!(TpiError::Bmc(format!(
+                "could not execute flashing: {}",
+                response.text().await?
+            )));
+        }
+
+        let accepts_gzip = accepts_gzip(&response);
+        let json: serde_json::Value = response.json().await?;
+        let FlashHandle { handle } = parse_response(&json)?;
+        Ok(Transfer {
+            handle,
+            accepts_gzip,
+        })
+    }
+
+    /// Sends the `length` bytes of `image` for `transfer`, written to the
+    /// connection in chunks of at most `max_frame_size` bytes.
+    pub async fn upload(
+        &self,
+        transfer: &Transfer,
+        image: impl AsyncRead + Unpin + Send + Sync + 'static,
+        length: u64,
+        max_frame_size: usize,
+    ) -> Result<()> {
+        let mut request = self.upload_request(transfer.handle)?;
+        request.set_multipart(multipart_form(image, length, max_frame_size)?);
+        self.send_upload(request).await
+    }
+
+    /// Like [`BmcClient::upload`], but compressed with gzip. Only for a
+    /// transfer that [`Transfer::accepts_gzip`].
+    pub async fn upload_gzip(
+        &self,
+        transfer: &Transfer,
+        image: impl AsyncRead + Unpin + Send + Sync + 'static,
+        max_frame_size: usize,
+    ) -> Result<()> {
+        let mut request = self.upload_request(transfer.handle)?;
+        let (body, content_type) = gzip_multipart_body(image, max_frame_size);
+        let headers = request.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(&content_type)?);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        request.set_body(body);
+        self.send_upload(request).await
+    }
+
+    /// Creates the request that carries the data of transfer `handle`.
+    fn upload_request(&self, handle: u64) -> Result<Request> {
+        let mut request = self.request.to_post()?;
+        request
+            .url_mut()
+            .path_segments_mut()
+            .unwrap()
+            .push("upload")
+            .push(&handle.to_string());
+        Ok(request)
+    }
+
+    async fn send_upload(&self, request: Request) -> Result<()> {
+        let response = request.send(self.client.clone()).await?;
+        if !response.status().is_success() {
+            bail!(TpiError::Bmc(format!(
+                "upload rejected by the BMC: {}",
+                response.text().await?
+            )));
+        }
+        Ok(())
+    }
+
+    /// Follows the flash identified by `handle`, polling every `interval`,
+    /// jittered by [`BmcClient::set_interval_jitter`], until it completes. Returns the number of bytes the BMC wrote, if it
+    /// reported it.
+    pub async fn watch_flash(
+        &self,
+        handle: u64,
+        interval: Duration,
+        mut on_event: impl FnMut(&FlashEvent),
+    ) -> Result<Option<u64>> {
+        let mut watcher = FlashWatcher::new(&self.request, handle);
+        loop {
+            if let Some(event) = watcher.poll(&self.client).await? {
+                on_event(&event);
+                if let FlashEvent::Done { recorded, .. } = event {
+                    return Ok(recorded);
+                }
+            }
+            tokio::time::sleep(jittered(interval, self.interval_jitter)).await;
+        }
+    }
+
+    /// Returns the key/value pairs that `tpi info` prints.
+    pub async fn info(&self) -> Result<Info> {
+        parse_result_object(&self.get("other").await?)
+    }
+
+    /// Returns whether node 1 to 4 are powered on.
+    pub async fn power_status(&self) -> Result<[bool; 4]> {
+        let response: PowerStatus = parse_result_object(&self.get("power").await?)?;
+        let mut status = [false; 4];
+        for (node, on) in (1..).zip(status.iter_mut()) {
+            *on = response
+                .is_on(node)
+                .with_context(|| format!("API error: no power state for node {node}"))?;
+        }
+        Ok(status)
+    }
+
+    /// Powers node 1 to 4 on or off.
+    pub async fn set_power(&self, node: u8, on: bool) -> Result<()> {
+        ensure_node(node)?;
+        self.set("power", &[(&format!("node{node}"), power_bit(on))])
+            .await
+    }
+
+    /// Powers all nodes on or off at once.
+    pub async fn set_power_all(&self, on: bool) -> Result<()> {
+        let nodes = ["node1", "node2", "node3", "node4"];
+        let params = nodes.map(|node| (node, power_bit(on)));
+        self.set("power", &params).await
+    }
+
+    /// Resets node 1 to 4.
+    pub async fn reset(&self, node: u8) -> Result<()> {
+        ensure_node(node)?;
+        self.set("reset", &[("node", api_node(node))]).await
+    }
+
+    /// Routes the USB-bus to node 1 to 4 in the given mode. With `bmc`, the
+    /// other end of the bus is the BMC chip instead of the USB-A port.
+    pub async fn set_usb_mode(&self, node: u8, mode: UsbMode, bmc: bool) -> Result<()> {
+        ensure_node(node)?;
+        let mut mode = match mode {
+            UsbMode::Host => 0,
+            UsbMode::Device => 1,
+            UsbMode::Flash => 2,
+        };
+        mode |= u8::from(bmc) << 2;
+
+        self.set(
+            "usb",
+            &[("node", api_node(node)), ("mode", mode.to_string())],
+        )
+        .await
+    }
+}
+
+/// Fails with a usage error unless `node` is 1 to 4.
+fn ensure_node(node: u8) -> Result<()> {
+    ensure!(
+        (1..=4).contains(&node),
+        TpiError::Usage(format!("there is no node {node}, expected 1 to 4"))
+    );
+    Ok(())
+}
+
+fn power_bit(on: bool) -> String {
+    if on { "1" } else { "0" }.to_string()
+}
+
+/// Tells whether the BMC advertised gzip in the `Accept-Encoding` header of
+/// its response.
+fn accepts_gzip(response: &reqwest::Response) -> bool {
+    response
+        .headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| coding.split(';').next().unwrap_or_default().trim() == "gzip")
+}
+
+/// Wraps `reader` in the multipart form of an upload of `length` bytes.
+fn multipart_form(
+    reader: impl AsyncRead + Unpin + Send + Sync + 'static,
+    length: u64,
+    max_frame_size: usize,
+) -> Result<Form> {
+    // Every chunk of the stream is written to the connection as a whole,
+    // which makes its capacity the frame size of the upload.
+    let stream = ReaderStream::with_capacity(reader, max_frame_size);
+    let part = Part::stream_with_length(Body::wrap_stream(stream), length)
+        .mime_str("application/octet-stream")?;
+    Ok(Form::new().part("file", part))
+}
+
+/// Builds a gzip compressed `multipart/form-data` body with `reader` as its
+/// only `file` part. Returns the body together with its content type. The
+/// body is built by hand as reqwest cannot compress a `Form`.
+fn gzip_multipart_body(
+    reader: impl AsyncRead + Unpin + Send + Sync + 'static,
+    max_frame_size: usize,
+) -> (Body, String) {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let boundary = format!("tpi-{nanos:x}");
+
+    let head = format!(
+        "--{boundary}\r\n\
+        Content-Disposition: form-data; name=\"file\"\r\n\
+        Content-Type: application/octet-stream\r\n\r\n"
+    );
+    let tail = format!("\r\n--{boundary}--\r\n");
+
+    let form = std::io::Cursor::new(head.into_bytes())
+        .chain(reader)
+        .chain(std::io::Cursor::new(tail.into_bytes()));
+    let encoder = GzipEncoder::new(BufReader::with_capacity(UPLOAD_BUFFER_SIZE, form));
+    let body = Body::wrap_stream(ReaderStream::with_capacity(encoder, max_frame_size));
+
+    (body, format!("multipart/form-data; boundary={boundary}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Serves the reply `respond` returns for the request line of each
+    /// request, e.g. `GET /api/bmc?opt=get HTTP/1.1`. Returns the address and
+    /// the request lines received so far.
+    async fn serve(respond: fn(&str) -> (u16, &'static str)) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let line = read_request(&mut stream).await;
+                let (status, body) = respond(&line);
+                log.lock().unwrap().push(line);
+                let reply = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+        (addr, received)
+    }
+
+    /// Reads a request, including its body, and returns its request line.
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut data = Vec::new();
+        let mut buf = [0; 8192];
+        let head_end = loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..read]);
+            if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&data[..head_end]).to_lowercase();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map_or(0, |length| length.trim().parse().unwrap());
+        let chunked = head.contains("transfer-encoding: chunked");
+        loop {
+            let body = &data[head_end..];
+            let complete = if chunked {
+                body.ends_with(b"0\r\n\r\n")
+            } else {
+                body.len() >= length
+            };
+            if complete {
+                break;
+            }
+            let read = stream.read(&mut buf).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..read]);
+        }
+        let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+        head.lines().next().unwrap_or_default().to_string()
+    }
+
+    fn bmc_client(addr: &str) -> BmcClient {
+        let creds = (Some("root".to_string()), Some("turing".to_string()));
+        let mut request =
+            Request::new(addr.to_string(), ApiVersion::V1_1, creds, true, "test").unwrap();
+        request.set_scheme(Scheme::Http);
+        BmcClient::with_request(request, Client::new())
+    }
+
+    #[tokio::test]
+    async fn rejected_upload_fails_before_watching_the_flash() {
+        let (addr, received) = serve(|line| {
+            if line.contains("/authenticate") {
+                (200, r#"{"id": "token"}"#)
+            } else if line.contains("/upload/") {
+                (400, "image rejected")
+            } else {
+                (200, r#"{"handle": 7}"#)
+            }
+        })
+        .await;
+
+        let params = FlashParams {
+            node: 1,
+            file_name: "image.img".into(),
+            size: 4,
+            ..Default::default()
+        };
+        let image = std::io::Cursor::new(b"data".to_vec());
+        let mut events = 0;
+        let err = bmc_client(&addr)
+            .flash(&params, image, Duration::ZERO, |_| events += 1)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err.downcast_ref(), Some(TpiError::Bmc(_))));
+        assert_eq!(
+            err.to_string(),
+            "upload rejected by the BMC: image rejected"
+        );
+        assert_eq!(events, 0);
+        let received = received.lock().unwrap();
+        assert!(received.iter().any(|line| line.contains("/upload/7")));
+        assert!(!received.iter().any(|line| line.contains("opt=get")));
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Failure classes of tpi, each mapping onto its own process exit code so
+//! that scripts can branch on the kind of failure.
+
+use std::fmt;
+
+/// Exit code of failures that do not fall in any of the classes below.
+pub const EXIT_FAILURE: u8 = 1;
+/// Exit code used when the BMC could not be reached.
+const EXIT_NETWORK: u8 = 11;
+
+#[derive(Debug)]
+pub enum TpiError {
+    /// Invalid or incomplete command line arguments. Exit code 2, which is
+    /// also used by the argument parser itself.
+    Usage(String),
+    /// The BMC rejected the credentials, or none could be obtained. Exit code 10.
+    Auth(String),
+    /// The BMC reported an error while executing the command. Exit code 12.
+    Bmc(String),
+    /// An integrity or verification check failed. Exit code 13.
+    Integrity(String),
+}
+
+impl TpiError {
+    pub fn code(&self) -> u8 {
+        match self {
+            TpiError::Usage(_) => 2,
+            TpiError::Auth(_) => 10,
+            TpiError::Bmc(_) => 12,
+            TpiError::Integrity(_) => 13,
+        }
+    }
+}
+
+impl fmt::Display for TpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TpiError::Usage(msg)
+            | TpiError::Auth(msg)
+            | TpiError::Bmc(msg)
+            | TpiError::Integrity(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for TpiError {}
+
+/// Returns the exit code for the given error. Connection failures are
+/// recognized from the underlying `reqwest` error.
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    if let Some(e) = error.downcast_ref::<TpiError>() {
+        return e.code();
+    }
+
+    let unreachable = error.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_connect() || e.is_timeout())
+    });
+
+    if unreachable {
+        EXIT_NETWORK
+    } else {
+        EXIT_FAILURE
+    }
+}
@@ -12,177 +12,622 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cli::{
+    api_node, node_from_base, node_to_base, parse_delay, CurvePoint, FlashArgs, LoginArgs,
+    ProgressBarStyle, RawArgs,
+};
 use crate::cli::{
     AdvancedArgs, ApiVersion, Cli, Commands, CoolingArgs, CoolingCmd, EthArgs, EthCmd,
-    FirmwareArgs, GetSet, PowerArgs, PowerCmd, UartArgs, UsbArgs,
+    FirmwareArgs, GetSet, InfoArgs, PowerArgs, PowerCmd, Scheme, UartArgs, UsbArgs,
 };
-use crate::cli::{FlashArgs, UsbCmd};
-use crate::request::Request;
+use crate::client::{self, BmcClient, FlashParams, Transfer, DEFAULT_MAX_FRAME_SIZE};
+use crate::error::TpiError;
+use crate::profile::Profiler;
+use crate::progress::{current_flash, jittered, FlashEvent, FlashWatcher};
+use crate::prompt;
+use crate::request::{self, Request};
+use crate::response::{
+    parse_response, parse_result, parse_result_object, CoolingDevice, CoolingStatus, FlashProgress,
+    Info, PowerStatus, SdcardStatus, UartOutput, UsbStatus,
+};
+use crate::utils::{build_progress_bar, build_spinner, cancel_on_ctrl_c};
 use anyhow::{bail, ensure, Context};
-use indicatif::{HumanBytes, ProgressBar, ProgressState, ProgressStyle};
-use platform_info::{PlatformInfo, PlatformInfoAPI, UNameAPI};
+use clap::ValueEnum;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar};
 use reqwest::multipart::Part;
-use reqwest::{Body, Client, ClientBuilder};
-use std::fmt::Write;
-use std::path::Path;
+use reqwest::{Client, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::str::from_utf8;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
-use tokio::time::sleep;
-use tokio::{spawn, task::JoinHandle};
-use tokio_util::io::ReaderStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_util::io::InspectReader;
+
+type ResponsePrinter = Box<dyn Fn(&serde_json::Value) -> anyhow::Result<()>>;
+/// Size of the buffer the image is read into from disk.
+const READ_BUFFER_SIZE: usize = 1024 * 32;
+/// Default of `--wait-timeout`.
+const WAIT_TCP_TIMEOUT: Duration = Duration::from_secs(300);
+/// Pause between two connection attempts of `power on --wait-ssh`.
+const WAIT_TCP_RETRY: Duration = Duration::from_secs(2);
+/// Default of `--timeout`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default of `--timeout` for `healthcheck`.
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default of `power cycle --delay`.
+const DEFAULT_CYCLE_DELAY: Duration = Duration::from_secs(3);
+/// Exit code of `power status --exit-code` for a node that is powered off.
+const EXIT_NODE_OFF: u8 = 3;
+/// Images below this size are most likely truncated downloads or stub files.
+const MIN_IMAGE_SIZE: u64 = 1024 * 1024;
+
+/// An image that is flashed to several nodes, opened and checksummed once.
+struct SharedImage {
+    file: File,
+    name: String,
+    size: u64,
+    sha256: Option<String>,
+}
 
-type ResponsePrinter = fn(&serde_json::Value) -> anyhow::Result<()>;
-/// specifies the size of the reader buffer. Increasing the size will also
-/// increase the frame size of files streamed over HTTP (up to its max fame
-/// size)
-const MULTIPART_BUFFER_SIZE: usize = 1024 * 32;
+#[derive(PartialEq, Eq)]
+enum FlashOutcome {
+    Flashed,
+    Failed,
+    /// Cancelled while in progress, the node may hold a partial image.
+    Interrupted,
+    /// Not started because of an earlier cancellation.
+    Skipped,
+}
 
 pub struct LegacyHandler {
     request: Request,
     client: Client,
+    /// Sends the requests of the operations the library implements.
+    bmc: BmcClient,
     response_printer: Option<ResponsePrinter>,
+    /// Overrides the raw JSON output of `--json` for the extracted response.
+    json_printer: Option<ResponsePrinter>,
     json: bool,
+    json_pretty: bool,
+    /// Hides progress bars and informational messages, see `--quiet`.
+    quiet: bool,
     skip_request: bool,
+    batch: bool,
+    progress_style: ProgressBarStyle,
+    interval_jitter: f64,
+    /// Bound of each request, `None` if unbounded. See `--timeout`.
+    timeout: Option<Duration>,
     version: ApiVersion,
+    profiler: Option<Profiler>,
+    /// Node used by commands that require one when `--node` is omitted.
+    default_node: Option<u8>,
+    /// Numbering of nodes in the output, see `--node-base`.
+    node_base: u8,
+    /// Extra query parameters passed after a trailing `--`.
+    query_params: Vec<(String, String)>,
+    /// Exit code of a command that completed without error, but whose outcome
+    /// is reported through the exit code, e.g. `healthcheck`.
+    exit_code: ExitCode,
 }
 
 impl LegacyHandler {
-    fn create_client(version: ApiVersion) -> anyhow::Result<Client> {
-        if version == ApiVersion::V1 {
-            return Ok(Client::new());
-        }
-
-        let client = ClientBuilder::new()
-            .gzip(true)
-            .danger_accept_invalid_certs(true)
-            .http1_only()
-            .https_only(true)
-            .build()?;
-        Ok(client)
-    }
-
-    pub fn new(host: String, args: &Cli) -> anyhow::Result<Self> {
-        let json = args.json;
+    pub fn new(host: String, args: &Cli, profiler: Option<Profiler>) -> anyhow::Result<Self> {
+        let json = args.json || args.json_pretty;
         let version = args.api_version.expect("Missing API version");
-        let creds = (args.user.clone(), args.password.clone());
-        let user_agent = PlatformInfo::new()
-            .map(|nfo| {
-                format!(
-                    "TPI ({};{};{})",
-                    nfo.sysname().to_string_lossy(),
-                    nfo.machine().to_string_lossy(),
-                    nfo.osname().to_string_lossy()
-                )
-            })
-            .unwrap_or("TPI".to_string());
-        let request = Request::new(host, version, creds, &user_agent)?;
-        let client = Self::create_client(version)?;
+        let password = read_password(args)?.or_else(|| args.password.clone());
+        let creds = netrc_fallback(args, password);
+        let mut request = Request::new(host, version, creds, args.batch, &client::user_agent())?;
+        if let Some(profiler) = &profiler {
+            request.set_profiler(profiler.clone());
+        }
+        if let Some(scheme) = args.scheme {
+            request.set_scheme(scheme);
+        }
+        request.set_retries(args.retries);
+        if let Some(path) = &args.token_cache {
+            request.set_token_cache(path.clone());
+        }
+        let keepalive = (args.tcp_keepalive > 0).then(|| Duration::from_secs(args.tcp_keepalive));
+        let timeout = request_timeout(args);
+        let client = client::create_http_client(
+            version,
+            request.scheme(),
+            args.batch,
+            keepalive,
+            timeout,
+            profiler.as_ref(),
+        )?;
+        let mut bmc = BmcClient::with_request(request.clone(), client.clone());
+        bmc.set_interval_jitter(args.interval_jitter);
 
         Ok(Self {
+            bmc,
             request,
             client,
             response_printer: None,
+            json_printer: None,
             json,
+            json_pretty: args.json_pretty,
+            quiet: args.quiet,
             skip_request: false,
+            batch: args.batch,
+            progress_style: args.progress_style,
+            interval_jitter: args.interval_jitter,
+            timeout,
             version,
+            profiler,
+            default_node: args.default_node,
+            node_base: args.node_base,
+            query_params: args.query_params.clone(),
+            exit_code: ExitCode::SUCCESS,
         })
     }
 
     /// Handler for CLI commands. Responses are printed to stdout and need to be formatted
     /// using the JSON format with a key `response`.
-    pub async fn handle_cmd(mut self, command: &Commands) -> anyhow::Result<()> {
+    pub async fn handle_cmd(mut self, command: &Commands) -> anyhow::Result<ExitCode> {
         match command {
-            Commands::Power(args) => self.handle_power_nodes(args)?,
-            Commands::Usb(args) => self.handle_usb(args)?,
+            Commands::Power(args) if args.cmd == PowerCmd::Sequence => {
+                self.handle_power_sequence(args).await?
+            }
+            Commands::Power(args) if args.wait_ssh.is_some() => {
+                self.handle_power_on_and_wait(args).await?
+            }
+            Commands::Power(args) => self.handle_power_nodes(args).await?,
+            Commands::Usb(args) => self.handle_usb(args).await?,
             Commands::Firmware(args) => self.handle_firmware(args).await?,
             Commands::Flash(args) => self.handle_flash(args).await?,
             Commands::Eth(args) => self.handle_eth(args)?,
-            Commands::Uart(args) => self.handle_uart(args)?,
+            Commands::Uart(args) => self.handle_uart(args).await?,
             Commands::Cooling(args) => self.handle_cooling(args).await?,
             Commands::Advanced(args) => self.handle_advanced(args).await?,
-            Commands::Info => self.handle_info(),
+            Commands::Info(args) => self.handle_info(args).await?,
+            Commands::Healthcheck => self.handle_healthcheck().await,
+            Commands::SelfTest => self.handle_self_test().await,
+            Commands::Login(args) => self.handle_login(args).await?,
             Commands::Reboot => self.handle_reboot(),
+            Commands::Raw(args) => self.handle_raw(args).await?,
             #[cfg(feature = "localhost")]
             Commands::Eeprom(args) => self.handle_eeporm(args).await?,
         }
 
         if self.skip_request {
-            return Ok(());
+            if !self.query_params.is_empty() {
+                eprintln!("Warning: extra query parameters are not supported by this command");
+            }
+            return Ok(self.exit_code);
         }
 
+        self.request
+            .url_mut()
+            .query_pairs_mut()
+            .extend_pairs(&self.query_params);
+
         let response = self.request.send(self.client).await?;
         let status = response.status();
+
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = request::retry_after(&response);
+            let body = response.text().await.unwrap_or_default();
+            bail!(TpiError::Bmc(busy_message(retry_after, &body)));
+        }
+
         let bytes = response.bytes().await?;
 
         let body: serde_json::Value = match serde_json::from_slice(&bytes) {
             Ok(b) => b,
-            Err(_) => bail!(
+            Err(_) => bail!(TpiError::Bmc(format!(
                 "{}:\n{}",
                 status.canonical_reason().unwrap_or("unknown reason"),
                 from_utf8(&bytes).unwrap_or("error parsing server response")
-            ),
+            ))),
         };
 
+        // Some replies, typically errors, are a bare string or array instead
+        // of an object with a `response` key.
+        if let serde_json::Value::String(_) | serde_json::Value::Array(_) = body {
+            let text = match &body {
+                serde_json::Value::Array(values) => values
+                    .iter()
+                    .map(display_value)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                value => display_value(value),
+            };
+            ensure!(status.is_success(), TpiError::Bmc(text));
+
+            if self.json {
+                print_json(&body, self.json_pretty);
+            } else {
+                println!("{text}");
+            }
+            return Ok(self.exit_code);
+        }
+
         if self.json {
-            println!("{}", &body.to_string());
-            return Ok(());
+            if let Some(printer) = &self.json_printer {
+                let extracted = body
+                    .get("response")
+                    .and_then(|r| r.get(0))
+                    .context("expected 'response' key in JSON payload")?;
+                return printer(extracted).map(|_| self.exit_code);
+            }
+
+            print_json(&body, self.json_pretty);
+            return Ok(self.exit_code);
         }
 
-        body.get("response")
-            .ok_or_else(|| anyhow::anyhow!("expected 'response' key in JSON payload"))
-            .map(|response| {
-                let extracted = response
-                    .as_array()
-                    .unwrap_or_else(|| panic!("API error: `response` is not an array"))
-                    .first()
-                    .unwrap_or_else(|| panic!("API error: `response` is empty"));
-                let default_print = || {
-                    // In this case there is no printer set, fallback on
-                    // printing the http response body as text.
-                    println!("{}", extracted);
-                };
-
-                self.response_printer.map_or_else(default_print, |f| {
-                    if let Err(e) = f(extracted) {
-                        default_print();
-                        println!("{}", e);
-                    }
-                });
-            })
+        let extracted = body.get("response").and_then(|r| r.get(0)).ok_or_else(|| {
+            TpiError::Bmc(format!(
+                "expected a non-empty 'response' array in JSON payload: {body}"
+            ))
+        })?;
+        let default_print = || {
+            // In this case there is no printer set, fallback on
+            // printing the http response body as text.
+            println!("{}", extracted);
+        };
+
+        self.response_printer.map_or_else(default_print, |f| {
+            if let Err(e) = f(extracted) {
+                default_print();
+                println!("{}", e);
+            }
+        });
+        Ok(self.exit_code)
     }
 
-    fn handle_info(&mut self) {
+    async fn handle_info(&mut self, args: &InfoArgs) -> anyhow::Result<()> {
         self.request
             .url_mut()
             .query_pairs_mut()
             .append_pair("opt", "get")
             .append_pair("type", "other");
 
-        self.response_printer = Some(info_printer);
+        let (version, scheme) = (self.version, self.request.scheme());
+        let Some(field) = &args.field else {
+            self.response_printer = Some(Box::new(move |map| info_printer(map, version, scheme)));
+            return Ok(());
+        };
+
+        // Queried here rather than through a printer, so that an unknown
+        // field fails the command with a usage error.
+        self.skip_request = true;
+        let response = self.query(self.request.clone()).await?;
+        let value = info_field(&response, version, field)?;
+        if self.json {
+            print_json(
+                &serde_json::json!({ field.as_str(): value }),
+                self.json_pretty,
+            );
+        } else {
+            println!("{value}");
+        }
+        Ok(())
     }
 
-    fn handle_uart(&mut self, args: &UartArgs) -> anyhow::Result<()> {
-        let mut serializer = self.request.url_mut().query_pairs_mut();
-        if args.action == GetSet::Get {
-            serializer
+    async fn handle_healthcheck(&mut self) {
+        self.skip_request = true;
+
+        let mut request = self.request.clone();
+        request
+            .url_mut()
+            .query_pairs_mut()
+            .append_pair("opt", "get")
+            .append_pair("type", "other");
+
+        let start = Instant::now();
+        let result = match self.timeout {
+            Some(limit) => match timeout(limit, self.query(request)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("no response within {}s", limit.as_secs())),
+            },
+            None => self.query(request).await,
+        };
+        let latency_ms = start.elapsed().as_millis();
+
+        match &result {
+            Ok(_) if self.json => print_json(
+                &serde_json::json!({"healthy": true, "latency_ms": latency_ms}),
+                self.json_pretty,
+            ),
+            Ok(_) => println!("OK: BMC responded in {latency_ms}ms"),
+            Err(e) if self.json => print_json(
+                &serde_json::json!({"healthy": false, "error": format!("{e:#}")}),
+                self.json_pretty,
+            ),
+            Err(e) => println!("CRITICAL: {e:#}"),
+        }
+
+        if result.is_err() {
+            self.exit_code = ExitCode::FAILURE;
+        }
+    }
+
+    async fn handle_self_test(&mut self) {
+        self.skip_request = true;
+
+        let api = self
+            .version
+            .to_possible_value()
+            .expect("api version has a value");
+        let mut results = serde_json::Map::new();
+        let mut failed = 0;
+
+        for (name, kind) in [
+            ("info", "other"),
+            ("power", "power"),
+            ("usb", "usb"),
+            ("cooling", "cooling"),
+        ] {
+            let mut request = self.request.clone();
+            request
+                .url_mut()
+                .query_pairs_mut()
                 .append_pair("opt", "get")
-                .append_pair("type", "uart")
-                .append_pair("node", &(args.node - 1).to_string());
-            self.response_printer = Some(uart_printer);
+                .append_pair("type", kind);
+
+            let result = match self.query(request).await {
+                Ok(_) => serde_json::json!({ "ok": true }),
+                Err(e) => {
+                    failed += 1;
+                    serde_json::json!({ "ok": false, "error": format!("{e:#}") })
+                }
+            };
+            results.insert(name.to_string(), result);
+        }
+
+        if self.json {
+            print_json(
+                &serde_json::json!({ "api": api.get_name(), "endpoints": results }),
+                self.json_pretty,
+            );
+        } else {
+            println!(
+                "api version: {} ({})",
+                api.get_name(),
+                self.request.scheme()
+            );
+            println!("|{:-^10}|{:-^28}|", "endpoint", "result");
+            for (name, result) in &results {
+                match result.get("error").and_then(|e| e.as_str()) {
+                    None => println!(" {:<10}: pass", name),
+                    Some(error) => println!(" {:<10}: FAIL {}", name, error),
+                }
+            }
+            println!("|{:-^10}|{:-^28}|", "", "");
+        }
+
+        if failed > 0 {
+            self.exit_code = ExitCode::FAILURE;
+        }
+    }
+
+    async fn handle_login(&mut self, args: &LoginArgs) -> anyhow::Result<()> {
+        self.skip_request = true;
+        let token = self.request.login(&self.client).await?;
+
+        if args.print_token {
+            println!("{token}");
         } else {
+            self.status("Logged in");
+        }
+        Ok(())
+    }
+
+    async fn handle_uart(&mut self, args: &UartArgs) -> anyhow::Result<()> {
+        if args.action == GetSet::Set {
             ensure!(
-                args.cmd.is_some(),
-                "uart set command requires `--cmd` argument."
+                args.cmd.is_some() || args.cmd_file.is_some(),
+                TpiError::Usage(
+                    "uart set command requires `--cmd` or `--cmd-file` argument.".into()
+                )
+            );
+        } else {
+            ensure!(
+                args.cmd_file.is_none(),
+                TpiError::Usage("`--cmd-file` can only be used with `set`".into())
+            );
+        }
+
+        if let Some(path) = &args.cmd_file {
+            return self.send_uart_file(args.node, path).await;
+        }
+
+        ensure!(
+            !args.follow || args.action == GetSet::Get,
+            TpiError::Usage("`--follow` can only be used with `get`".into())
+        );
+
+        // Unlike a single `get`, following needs one node, which may come
+        // from `--default-node`.
+        if args.follow {
+            let node = require_node(args.node, self.default_node)?;
+            return self.follow_uart(args, node).await;
+        }
+
+        let Some(node) = args.node else {
+            return self.handle_uart_all_nodes(args).await;
+        };
+
+        append_uart_query(&mut self.request, args, node, args.cmd.as_deref());
+        if args.action == GetSet::Get {
+            self.response_printer = Some(Box::new(uart_printer));
+        } else {
+            self.response_printer = Some(Box::new(result_printer));
+        }
+        Ok(())
+    }
+
+    async fn handle_uart_all_nodes(&mut self, args: &UartArgs) -> anyhow::Result<()> {
+        self.skip_request = true;
+
+        for node in 1..=4 {
+            let mut request = self.request.clone();
+            append_uart_query(&mut request, args, node, args.cmd.as_deref());
+            let response = self.query(request).await?;
+            let node = self.shown(node);
+
+            if self.json {
+                print_json(
+                    &serde_json::json!({ "node": node, "response": response }),
+                    self.json_pretty,
+                );
+                continue;
+            }
+
+            match args.action {
+                GetSet::Get => {
+                    let output: UartOutput = parse_response(&response)?;
+                    for line in output.uart.lines() {
+                        println!("[node {node}] {line}");
+                    }
+                }
+                GetSet::Set => {
+                    let result: String = parse_result(&response)?;
+                    println!("[node {node}] {result}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of `path` to the UART of `node`, or of all nodes,
+    /// in chunks of at most `UART_CHUNK_SIZE` bytes.
+    async fn send_uart_file(&mut self, node: Option<u8>, path: &Path) -> anyhow::Result<()> {
+        self.skip_request = true;
+
+        let input = if path == Path::new("-") {
+            std::io::read_to_string(std::io::stdin())
+                .context("cannot read UART input from stdin")?
+        } else {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read {}", path.display()))?
+        };
+        ensure!(
+            !input.is_empty(),
+            TpiError::Usage("the UART input is empty, nothing to send".into())
+        );
+
+        let nodes = node.map_or(vec![1, 2, 3, 4], |node| vec![node]);
+        for node in nodes {
+            let mut result = String::new();
+            for chunk in uart_chunks(&input) {
+                let mut request = self.request.clone();
+                request
+                    .url_mut()
+                    .query_pairs_mut()
+                    .append_pair("opt", "set")
+                    .append_pair("type", "uart")
+                    .append_pair("node", &api_node(node))
+                    .append_pair("cmd", chunk);
+                result = parse_result(&self.query(request).await?)?;
+            }
+
+            if self.json {
+                print_json(
+                    &serde_json::json!({ "node": self.shown(node), "bytes": input.len(), "result": result }),
+                    self.json_pretty,
+                );
+            } else {
+                println!("[node {}] {result}", self.shown(node));
+            }
+        }
+        Ok(())
+    }
+
+    /// Polls the UART of `node` and prints what was added since the previous
+    /// poll, until Ctrl-C.
+    async fn follow_uart(&mut self, args: &UartArgs, node: u8) -> anyhow::Result<()> {
+        self.skip_request = true;
+
+        let mut request = self.request.clone();
+        append_uart_query(&mut request, args, node, None);
+        let interval = Duration::from_millis(args.interval);
+        let cancel = cancel_on_ctrl_c();
+        let mut previous = String::new();
+        loop {
+            let response = tokio::select! {
+                response = self.query(request.clone()) => response?,
+                _ = cancel.cancelled() => return Ok(()),
+            };
+            let UartOutput { uart } = parse_response(&response)?;
+
+            let output = new_uart_output(&previous, &uart);
+            if !output.is_empty() {
+                if self.json {
+                    print_json(&serde_json::json!({ "uart": output }), self.json_pretty);
+                } else {
+                    print!("{output}");
+                }
+                std::io::stdout().flush()?;
+            }
+            previous = uart;
+
+            tokio::select! {
+                _ = sleep(jittered(interval, self.interval_jitter)) => {}
+                _ = cancel.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    /// Returns how the 1-based `node` is presented to the user.
+    fn shown(&self, node: u8) -> u8 {
+        node_to_base(node, self.node_base)
+    }
+
+    /// Sends `request` and returns the first element of the `response` array
+    /// of the reply.
+    async fn query(&self, request: Request) -> anyhow::Result<serde_json::Value> {
+        client::query(&self.client, request).await
+    }
+
+    async fn handle_raw(&mut self, args: &RawArgs) -> anyhow::Result<()> {
+        if args.op == GetSet::Set && !args.yes {
+            ensure!(
+                !self.batch,
+                TpiError::Usage("pass `--yes` to send a `set` request in batch mode".into())
+            );
+            ensure!(
+                prompt::confirm("Send this `set` request to the BMC? [y/N]")?,
+                TpiError::Usage("aborted".into())
             );
-            serializer
-                .append_pair("opt", "set")
-                .append_pair("type", "uart")
-                .append_pair("node", &(args.node - 1).to_string())
-                .append_pair("cmd", args.cmd.as_ref().unwrap());
-            self.response_printer = Some(result_printer);
         }
+
+        let op = args.op.to_possible_value().expect("no skipped variants");
+        let mut request = self.request.clone();
+        request
+            .url_mut()
+            .query_pairs_mut()
+            .append_pair("opt", op.get_name())
+            .append_pair("type", &args.kind)
+            .extend_pairs(&args.params)
+            .extend_pairs(std::mem::take(&mut self.query_params));
+
+        // The body is printed whole, whatever its shape, as this is meant for
+        // endpoints tpi knows nothing about.
+        self.skip_request = true;
+        let response = request.send(self.client.clone()).await?;
+        let status = response.status();
+        let bytes = response.bytes().await?;
+        let body: serde_json::Value = serde_json::from_slice(&bytes).map_err(|_| {
+            TpiError::Bmc(format!(
+                "{status}:\n{}",
+                from_utf8(&bytes).unwrap_or("error parsing server response")
+            ))
+        })?;
+        ensure!(
+            status.is_success(),
+            TpiError::Bmc(format!("{status}: {body}"))
+        );
+        print_json(&body, self.json_pretty);
         Ok(())
     }
 
@@ -192,12 +637,26 @@ impl LegacyHandler {
             .query_pairs_mut()
             .append_pair("opt", "set")
             .append_pair("type", "reboot");
-        self.response_printer = Some(result_printer);
+        self.response_printer = Some(Box::new(result_printer));
     }
 
     fn handle_eth(&mut self, args: &EthArgs) -> anyhow::Result<()> {
         match args.cmd {
             EthCmd::Reset => {
+                eprintln!(
+                    "Note: resetting the switch briefly drops the network connection of all nodes"
+                );
+                if !args.yes {
+                    ensure!(
+                        !self.batch,
+                        TpiError::Usage("pass `--yes` to reset the switch in batch mode".into())
+                    );
+                    ensure!(
+                        prompt::confirm("Reset the ethernet switch? [y/N]")?,
+                        TpiError::Usage("aborted".into())
+                    );
+                }
+
                 self.request
                     .url_mut()
                     .query_pairs_mut()
@@ -207,11 +666,14 @@ impl LegacyHandler {
             }
         }
 
-        self.response_printer = Some(result_printer);
+        self.response_printer = Some(Box::new(result_printer));
         Ok(())
     }
 
     async fn handle_firmware(&mut self, args: &FirmwareArgs) -> anyhow::Result<()> {
+        self.require_v1_1(args.sha256.is_some(), "`--sha256`")?;
+        self.require_v1_1(args.compress, "`--compress`")?;
+        ensure_readable_file(&args.file)?;
         let (mut file, file_name, size) = Self::open_file(&args.file).await?;
         if self.version == ApiVersion::V1 {
             // Opt out of the global request/response handler as we implement an
@@ -233,14 +695,101 @@ impl LegacyHandler {
                 .append_pair("type", "firmware")
                 .append_pair("file", &file_name)
                 .append_pair("length", &size.to_string());
-            if let Some(sha256) = &args.sha256 {
+            let sha256 = self
+                .resolve_sha256(args.sha256.as_deref(), &mut file, size)
+                .await?;
+            if let Some(sha256) = &sha256 {
                 self.request
                     .url_mut()
                     .query_pairs_mut()
                     .append_pair("sha256", sha256);
             }
-            self.handle_file_upload_v1_1(file, size).await
+            let transfer = self
+                .bmc
+                .start_firmware_upgrade(&file_name, size, sha256.as_deref())
+                .await?;
+            let intervals = progress_intervals(args.progress_delay, args.progress_interval);
+            let recorded = self
+                .handle_file_upload_v1_1(
+                    transfer,
+                    (file, size),
+                    intervals,
+                    false,
+                    args.compress,
+                    DEFAULT_MAX_FRAME_SIZE,
+                )
+                .await?;
+            check_recorded_length(self.json, size, recorded, false)
+        }
+    }
+
+    /// Fails with a usage error if `feature` is `used` on API version v1,
+    /// which does not support it.
+    fn require_v1_1(&self, used: bool, feature: &str) -> anyhow::Result<()> {
+        ensure!(
+            !used || self.version != ApiVersion::V1,
+            TpiError::Usage(format!("{feature} requires API version v1-1"))
+        );
+        Ok(())
+    }
+
+    /// Streams the image at `url` to the BMC, without storing it locally.
+    async fn flash_url(&mut self, args: &FlashArgs, url: &Url, node: u8) -> anyhow::Result<()> {
+        let (response, file_name, file_size) = open_url(url).await?;
+        self.status(format!(
+            "request flashing of {file_name} ({}) to node {}",
+            HumanBytes(file_size),
+            self.shown(node)
+        ));
+
+        let params = flash_params(args, &file_name, file_size, node, args.sha256.as_deref());
+        params.append_to(&mut self.request);
+        let transfer = self.bmc.start_flash(&params).await?;
+        let intervals = progress_intervals(args.progress_delay, args.progress_interval);
+        let recorded = self
+            .handle_file_upload_v1_1(
+                transfer,
+                (response_reader(response), file_size),
+                intervals,
+                args.print_digest,
+                args.compress,
+                args.max_frame_size as usize,
+            )
+            .await?;
+        check_recorded_length(self.json, file_size, recorded, args.strict_length)
+    }
+
+    /// Returns the checksum passed with `--sha256`. For `auto`, the digest of
+    /// `file` is computed and printed; `file` is rewound afterwards.
+    async fn resolve_sha256(
+        &self,
+        sha256: Option<&str>,
+        file: &mut File,
+        size: u64,
+    ) -> anyhow::Result<Option<String>> {
+        match sha256 {
+            Some("auto") => {}
+            sha256 => return Ok(sha256.map(str::to_string)),
+        }
+
+        self.status("computing sha256..");
+        let pb = self.progress_bar(size);
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; READ_BUFFER_SIZE];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            pb.inc(read as u64);
         }
+        pb.finish_and_clear();
+        file.seek(std::io::SeekFrom::Start(0)).await?;
+
+        let digest = hex::encode(hasher.finalize());
+        self.status(format!("sha256: {digest}"));
+        Ok(Some(digest))
     }
 
     async fn open_file(path: &Path) -> anyhow::Result<(File, String, u64)> {
@@ -252,6 +801,10 @@ impl LegacyHandler {
 
         let file_size = file.seek(std::io::SeekFrom::End(0)).await?;
         file.seek(std::io::SeekFrom::Start(0)).await?;
+        ensure!(
+            file_size > 0,
+            TpiError::Usage(format!("image is empty: {}", path.display()))
+        );
 
         let file_name = path
             .file_name()
@@ -265,157 +818,652 @@ impl LegacyHandler {
         // Opt out of the global request/response handler as we implement an alternative flow here.
         self.skip_request = true;
 
-        if args.local {
-            return self.handle_local_file_upload(args).await;
+        // v1 accepts these parameters, but ignores them. Better to refuse
+        // than to let a checksum pass for verified when it never was.
+        self.require_v1_1(args.sha256.is_some(), "`--sha256`")?;
+        self.require_v1_1(args.print_digest, "`--print-digest`")?;
+        self.require_v1_1(args.compress, "`--compress`")?;
+        self.require_v1_1(args.strict_length, "`--strict-length`")?;
+
+        // Refuse up front rather than flash and then skip the comparison.
+        ensure!(
+            !args.verify_after,
+            TpiError::Usage(
+                "the BMC exposes no read-back endpoint; --verify-after is unsupported".into()
+            )
+        );
+
+        if args.image_path.as_deref().and_then(image_url).is_some() {
+            self.require_v1_1(true, "flashing from a URL")?;
+            ensure!(
+                !args.local,
+                TpiError::Usage("`--local` takes a path on the BMC, not a URL".into())
+            );
+            ensure!(
+                args.sha256.as_deref() != Some("auto"),
+                TpiError::Usage(
+                    "`--sha256 auto` needs a local image, pass the checksum of the URL instead"
+                        .into()
+                )
+            );
         }
 
-        let (mut file, file_name, file_size) = Self::open_file(&args.image_path).await?;
-        println!("request flashing of {file_name} to node {}", args.node);
-
-        self.request
-            .url_mut()
-            .query_pairs_mut()
-            .append_pair("opt", "set")
-            .append_pair("type", "flash")
-            .append_pair("file", &file_name)
-            .append_pair("length", &file_size.to_string())
-            .append_pair("node", &(args.node - 1).to_string());
-
-        if let Some(sha256) = &args.sha256 {
-            self.request
-                .url_mut()
-                .query_pairs_mut()
-                .append_pair("sha256", sha256);
+        if args.dry_run {
+            return self.dry_run_flash(args).await;
         }
 
         if args.skip_crc {
-            self.request
-                .url_mut()
-                .query_pairs_mut()
-                .append_key_only("skip_crc");
+            eprintln!("Warning: CRC verification disabled; written data will not be validated");
+            if !args.yes {
+                ensure!(
+                    !self.batch,
+                    TpiError::Usage("pass `--yes` to use `--skip-crc` in batch mode".into())
+                );
+                ensure!(
+                    prompt::confirm("Flash without CRC verification? [y/N]")?,
+                    TpiError::Usage("aborted".into())
+                );
+            }
         }
 
-        if self.version == ApiVersion::V1 {
-            self.handle_file_upload_v1(&mut file, file_name).await
-        } else {
-            self.handle_file_upload_v1_1(file, file_size).await
+        if let Some(dir) = &args.image_dir {
+            return self.handle_flash_dir(args, dir).await;
         }
-    }
-
-    async fn handle_local_file_upload(&mut self, args: &FlashArgs) -> anyhow::Result<()> {
-        self.request
-            .url_mut()
-            .query_pairs_mut()
-            .append_pair("opt", "set")
-            .append_pair("type", "flash")
-            .append_key_only("local")
-            .append_pair("file", &args.image_path.to_string_lossy())
-            .append_pair("node", &(args.node - 1).to_string());
 
-        let response = self.request.clone().send(self.client.clone()).await?;
-        let status = response.status();
-        let json_res = response.json::<serde_json::Value>().await;
-
-        if !status.is_success() {
-            if let Ok(json) = &json_res {
-                if let Some(err) = json.get("response") {
-                    println!("Error: {}", err);
-                }
-            }
-            bail!("Failed to begin flashing: {}", status);
+        let image_path = args.image_path.as_deref().expect("clap requires an image");
+        let nodes = self.flash_target_nodes(args)?;
+        if nodes.len() > 1 {
+            return self.flash_nodes(args, image_path, nodes).await;
         }
+        let node = nodes[0];
 
-        let handle_id = get_json_num(&json_res?, "handle");
-
-        println!("Flashing from image file {}...", args.image_path.display());
+        if !args.local && image_url(image_path).is_none() {
+            ensure_readable_file(image_path)?;
+        }
 
-        let progress_watcher = self.create_progress_watching_thread(handle_id);
+        if args.power_off_during {
+            self.status(format!("powering off node {}", self.shown(node)));
+            self.set_node_power(node, false).await?;
+        }
 
-        progress_watcher.await.expect("failed to wait for thread");
+        if args.local {
+            self.handle_local_file_upload(args, image_path, node)
+                .await?;
+        } else {
+            self.flash_image(args, image_path, node).await?;
+        }
 
+        if args.power_on_after {
+            self.status(format!("powering on node {}", self.shown(node)));
+            self.set_node_power(node, true).await?;
+        }
         Ok(())
     }
 
-    fn create_progress_watching_thread(&self, handle_id: u64) -> JoinHandle<()> {
-        let initial_delay = Duration::from_secs(3);
-        let update_period = Duration::from_millis(500);
+    /// Returns the nodes given by `--node` or `--all`, `--default-node` if
+    /// neither was passed.
+    fn flash_target_nodes(&self, args: &FlashArgs) -> anyhow::Result<Vec<u8>> {
+        let mut nodes = if args.all {
+            vec![1, 2, 3, 4]
+        } else {
+            args.node.clone()
+        };
+        nodes.sort_unstable();
+        nodes.dedup();
+        if nodes.is_empty() {
+            nodes.push(require_node(None, self.default_node)?);
+        }
+        Ok(nodes)
+    }
 
-        let client = self.client.clone();
-        let mut req = self.request.clone();
+    /// Checks the images and arguments of a flash and prints the requests
+    /// that would start it, without sending anything.
+    async fn dry_run_flash(&mut self, args: &FlashArgs) -> anyhow::Result<()> {
+        if let Some(sha256) = &args.sha256 {
+            ensure!(
+                sha256 == "auto"
+                    || (sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit())),
+                TpiError::Usage(format!(
+                    "`{sha256}` is not a sha256 checksum, expected 64 hexadecimal digits"
+                ))
+            );
+        }
 
-        req.url_mut()
-            .query_pairs_mut()
-            .clear()
-            .append_pair("opt", "get")
-            .append_pair("type", "flash");
-
-        spawn(async move {
-            let mut bar: Option<ProgressBar> = None;
-            let mut verifying = false;
-
-            sleep(initial_delay).await;
-
-            loop {
-                let response = req
-                    .clone()
-                    .send(client.clone())
-                    .await
-                    .expect("Failed to send progress status request");
-
-                let status = response.status();
-                let json = response
-                    .json::<serde_json::Value>()
-                    .await
-                    .expect("Failed to parse response as JSON");
-
-                if !status.is_success() {
-                    if let Some(err) = json.get("response") {
-                        println!("Error: {}", err);
-                    }
-                    panic!("Failed to get flashing progress: {}", status);
+        let targets = match (&args.image_dir, &args.image_path) {
+            (Some(dir), _) => find_node_images(dir, self.node_base)?,
+            (None, Some(image)) => self
+                .flash_target_nodes(args)?
+                .into_iter()
+                .map(|node| (node, image.clone()))
+                .collect(),
+            (None, None) => unreachable!("clap requires an image"),
+        };
+        ensure!(!targets.is_empty(), "no images to flash");
+
+        for (node, image) in targets {
+            let (file_name, file_size) = match image_url(&image) {
+                Some(url) => {
+                    let (_, file_name, file_size) = open_url(&url).await?;
+                    (file_name, file_size)
+                }
+                None => {
+                    ensure_readable_file(&image)?;
+                    let (_, file_name, file_size) = Self::open_file(&image).await?;
+                    (file_name, file_size)
                 }
+            };
+            let sha256 = args.sha256.as_deref().filter(|sha256| *sha256 != "auto");
 
-                if let Some(map) = json.get("Transferring") {
-                    let id = get_json_num(map, "id");
-                    assert_eq!(id, handle_id, "Invalid flashing handle");
+            let mut request = self.request.clone();
+            flash_params(args, &file_name, file_size, node, sha256).append_to(&mut request);
+            print_status(
+                self.json,
+                format!(
+                    "node {}: {file_name} ({})",
+                    self.shown(node),
+                    HumanBytes(file_size)
+                ),
+            );
+            print_status(self.json, format!(" request: {}", request.url()));
+        }
 
-                    let file_size = get_json_num(map, "size");
+        if args.sha256.as_deref() == Some("auto") {
+            print_status(self.json, "the sha256 is computed when flashing");
+        }
+        print_status(self.json, "dry run, nothing was sent");
+        Ok(())
+    }
 
-                    if let Some(bar) = &mut bar {
-                        let bytes_written = get_json_num(map, "bytes_written");
+    /// Flashes the same image to several nodes.
+    async fn flash_nodes(
+        &mut self,
+        args: &FlashArgs,
+        image_path: &Path,
+        nodes: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            !args.local && !args.power_off_during,
+            TpiError::Usage(
+                "`--local` and `--power-off-during` can only be used with a single node".into()
+            )
+        );
+        let targets = nodes
+            .into_iter()
+            .map(|node| (node, image_path.to_path_buf()))
+            .collect();
+        if image_url(image_path).is_some() {
+            return self.flash_each(args, targets, None).await;
+        }
 
-                        if bytes_written >= file_size {
-                            if !verifying {
-                                bar.finish_and_clear();
-                                *bar = build_spinner();
-                                bar.set_message("Verifying checksum...");
-                                verifying = true;
-                            }
-                        } else {
-                            bar.set_position(bytes_written);
-                        }
-                    } else {
-                        bar = Some(build_progress_bar(file_size));
+        // The image is opened, and its digest computed, once for all nodes.
+        ensure_readable_file(image_path)?;
+        let (mut file, name, size) = Self::open_file(image_path).await?;
+        self.warn_about_image(args, image_path, &name, size);
+        let sha256 = self
+            .resolve_sha256(args.sha256.as_deref(), &mut file, size)
+            .await?;
+        let image = SharedImage {
+            file,
+            name,
+            size,
+            sha256,
+        };
+        self.flash_each(args, targets, Some(&image)).await
+    }
+
+    async fn handle_flash_dir(&mut self, args: &FlashArgs, dir: &Path) -> anyhow::Result<()> {
+        let images = find_node_images(dir, self.node_base)?;
+        ensure!(
+            !images.is_empty(),
+            "no images named `node{}`..`node{}` found in {}",
+            self.shown(1),
+            self.shown(4),
+            dir.display()
+        );
+        self.flash_each(args, images, None).await
+    }
+
+    /// Flashes each image to its node, one after another, or `shared` to all
+    /// of them. A node that fails does not stop the others; a summary tells
+    /// how each node fared.
+    async fn flash_each(
+        &mut self,
+        args: &FlashArgs,
+        images: Vec<(u8, PathBuf)>,
+        shared: Option<&SharedImage>,
+    ) -> anyhow::Result<()> {
+        // A Ctrl-C stops the node being flashed and skips the remaining ones,
+        // the summary still tells which nodes were flashed.
+        let cancel = cancel_on_ctrl_c();
+        let request = self.request.clone();
+        let mut results = Vec::new();
+        for (node, image) in images {
+            if cancel.is_cancelled() {
+                results.push((node, image, FlashOutcome::Skipped));
+                continue;
+            }
+
+            let shown = self.shown(node);
+            self.status(format!("node {shown} <- {}", image.display()));
+            self.request = request.clone();
+            let outcome = tokio::select! {
+                result = self.flash_target(args, &image, shared, node) => match result {
+                    Ok(()) => FlashOutcome::Flashed,
+                    Err(e) => {
+                        eprintln!("flashing node {shown} failed: {e:#}");
+                        FlashOutcome::Failed
                     }
+                },
+                _ = cancel.cancelled() => FlashOutcome::Interrupted,
+            };
+            results.push((node, image, outcome));
+        }
+
+        print_status(self.json, "Summary:");
+        for (node, image, outcome) in &results {
+            let outcome = match outcome {
+                FlashOutcome::Flashed => "ok",
+                FlashOutcome::Failed => "failed",
+                FlashOutcome::Interrupted => "interrupted, may have been partially written",
+                FlashOutcome::Skipped => "skipped",
+            };
+            print_status(
+                self.json,
+                format!(
+                    " node {}: {outcome} ({})",
+                    self.shown(*node),
+                    image.display()
+                ),
+            );
+        }
+
+        ensure!(!cancel.is_cancelled(), "flashing was interrupted");
+        let failed = results
+            .iter()
+            .filter(|(_, _, outcome)| *outcome != FlashOutcome::Flashed)
+            .count();
+        ensure!(
+            failed == 0,
+            "{failed} of {} nodes failed to flash",
+            results.len()
+        );
+        Ok(())
+    }
+
+    /// Flashes `shared` to `node` if given, the image at `image_path`
+    /// otherwise.
+    async fn flash_target(
+        &mut self,
+        args: &FlashArgs,
+        image_path: &Path,
+        shared: Option<&SharedImage>,
+        node: u8,
+    ) -> anyhow::Result<()> {
+        let Some(image) = shared else {
+            return self.flash_image(args, image_path, node).await;
+        };
+
+        // A clone shares the position of the original, which the previous
+        // node left at the end.
+        let mut file = image.file.try_clone().await?;
+        file.seek(std::io::SeekFrom::Start(0)).await?;
+        self.flash_file(
+            args,
+            file,
+            image.name.clone(),
+            image.size,
+            node,
+            image.sha256.as_deref(),
+        )
+        .await
+    }
+
+    async fn flash_image(
+        &mut self,
+        args: &FlashArgs,
+        image_path: &Path,
+        node: u8,
+    ) -> anyhow::Result<()> {
+        if let Some(url) = image_url(image_path) {
+            return self.flash_url(args, &url, node).await;
+        }
+
+        let (mut file, file_name, file_size) = Self::open_file(image_path).await?;
+        self.warn_about_image(args, image_path, &file_name, file_size);
+
+        let sha256 = self
+            .resolve_sha256(args.sha256.as_deref(), &mut file, file_size)
+            .await?;
+        self.flash_file(args, file, file_name, file_size, node, sha256.as_deref())
+            .await
+    }
+
+    /// Warns about images that are unlikely to be what the user meant to
+    /// flash.
+    fn warn_about_image(
+        &self,
+        args: &FlashArgs,
+        image_path: &Path,
+        file_name: &str,
+        file_size: u64,
+    ) {
+        if is_block_device(image_path) {
+            print_status(
+                self.json,
+                format!(
+                    "Warning: {} is a block device, all of its {} will be flashed. \
+                    Make sure it is the disk you meant.",
+                    image_path.display(),
+                    HumanBytes(file_size)
+                ),
+            );
+        } else if file_size < MIN_IMAGE_SIZE && !args.force {
+            print_status(
+                self.json,
+                format!(
+                    "Warning: {file_name} is only {}, this does not look like an OS image. \
+                    Pass `--force` to silence this warning.",
+                    HumanBytes(file_size)
+                ),
+            );
+        }
+    }
+
+    /// Uploads `file` from its current position to `node`, with `sha256` as
+    /// the checksum the BMC verifies.
+    async fn flash_file(
+        &mut self,
+        args: &FlashArgs,
+        mut file: File,
+        file_name: String,
+        file_size: u64,
+        node: u8,
+        sha256: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.status(format!(
+            "request flashing of {file_name} ({}) to node {}",
+            HumanBytes(file_size),
+            self.shown(node)
+        ));
+
+        let params = flash_params(args, &file_name, file_size, node, sha256);
+        params.append_to(&mut self.request);
+
+        if self.version == ApiVersion::V1 {
+            self.handle_file_upload_v1(&mut file, file_name).await
+        } else {
+            let transfer = self.bmc.start_flash(&params).await?;
+            let intervals = progress_intervals(args.progress_delay, args.progress_interval);
+            let recorded = self
+                .handle_file_upload_v1_1(
+                    transfer,
+                    (file, file_size),
+                    intervals,
+                    args.print_digest,
+                    args.compress,
+                    args.max_frame_size as usize,
+                )
+                .await?;
+            check_recorded_length(self.json, file_size, recorded, args.strict_length)
+        }
+    }
+
+    async fn handle_local_file_upload(
+        &mut self,
+        args: &FlashArgs,
+        image_path: &Path,
+        node: u8,
+    ) -> anyhow::Result<()> {
+        let free = self.sdcard_free_space().await;
+
+        self.request
+            .url_mut()
+            .query_pairs_mut()
+            .append_pair("opt", "set")
+            .append_pair("type", "flash")
+            .append_key_only("local")
+            .append_pair("file", &image_path.to_string_lossy())
+            .append_pair("node", &api_node(node));
+
+        if let Some(target) = &args.target {
+            self.request
+                .url_mut()
+                .query_pairs_mut()
+                .append_pair("device", target);
+        }
+
+        let start = Instant::now();
+
+        let response = self.request.clone().send(self.client.clone()).await?;
+        let status = response.status();
+        let json_res = response.json::<serde_json::Value>().await;
 
-                    sleep(update_period).await;
-                    continue;
+        if !status.is_success() {
+            if let Ok(json) = &json_res {
+                if let Some(err) = json.get("response") {
+                    eprintln!("Error: {}", err);
                 }
+            }
+            bail!(TpiError::Bmc(format!(
+                "Failed to begin flashing: {}",
+                status
+            )));
+        }
 
-                if json.get("Done").is_some() {
-                    println!("Done");
-                    break;
+        let handle_id = json_res?
+            .get("handle")
+            .and_then(serde_json::Value::as_u64)
+            .ok_or_else(|| {
+                TpiError::Bmc("BMC did not return a flash handle; cannot track progress".into())
+            })?;
+        self.print_handle(handle_id);
+
+        // The BMC only reports the size of the source image once it started
+        // reading it, which is still long before it runs out of space.
+        if let Some(free) = free {
+            let progress = current_flash(&self.client, &self.request).await;
+            if let Ok(FlashProgress::Transferring { size, .. }) = progress {
+                if free < size {
+                    print_status(
+                        self.json,
+                        format!(
+                            "Warning: the microSD card has {} free, less than the {} of the image",
+                            HumanBytes(free),
+                            HumanBytes(size)
+                        ),
+                    );
                 }
+            }
+        }
 
-                if let Some(map) = json.get("Error") {
-                    eprintln!("Error occured during flashing: {}", map);
-                    return;
+        self.status(format!(
+            "Flashing from image file {}...",
+            image_path.display()
+        ));
+
+        let intervals = progress_intervals(args.progress_delay, args.progress_interval);
+        self.watch_progress(handle_id, intervals, start).await?;
+        Ok(())
+    }
+
+    /// Prints informational `msg`, unless `--quiet`.
+    fn status(&self, msg: impl std::fmt::Display) {
+        if !self.quiet {
+            print_status(self.json, msg);
+        }
+    }
+
+    /// Creates a progress bar for `size` bytes, hidden with `--quiet`.
+    fn progress_bar(&self, size: u64) -> ProgressBar {
+        if self.quiet {
+            return ProgressBar::hidden();
+        }
+        build_progress_bar(size, self.progress_style)
+    }
+
+    /// Creates a spinner, hidden with `--quiet`.
+    fn spinner(&self) -> ProgressBar {
+        if self.quiet {
+            return ProgressBar::hidden();
+        }
+        build_spinner(self.progress_style)
+    }
+
+    /// Prints the id the BMC assigned to a transfer, which identifies it in
+    /// the logs of the BMC.
+    fn print_handle(&self, handle: u64) {
+        if self.json {
+            print_json(&serde_json::json!({ "handle": handle }), self.json_pretty);
+        } else if !self.quiet {
+            println!("transfer handle: {handle}");
+        }
+    }
+
+    /// Queries the free space of the BMC's microSD card. Returns `None` when the
+    /// firmware does not expose this information.
+    async fn sdcard_free_space(&self) -> Option<u64> {
+        let mut req = self.request.clone();
+        req.url_mut()
+            .query_pairs_mut()
+            .append_pair("opt", "get")
+            .append_pair("type", "sdcard");
+
+        let response = self.query(req).await.ok()?;
+        let status: SdcardStatus = parse_result_object(&response).ok()?;
+        Some(status.free)
+    }
+
+    /// Polls the progress of flash `handle_id` until the BMC reports it
+    /// done, then prints a summary of the flash that began at `start`. With
+    /// `--json`, progress is printed as one JSON object per change instead of
+    /// a progress bar. Returns the number of bytes the BMC wrote, if it
+    /// reported it.
+    async fn watch_progress(
+        &self,
+        handle_id: u64,
+        (initial_delay, update_period): (Duration, Duration),
+        start: Instant,
+    ) -> anyhow::Result<Option<u64>> {
+        let mut watcher = FlashWatcher::new(&self.request, handle_id);
+        let mut bar: Option<ProgressBar> = None;
+        let mut size = None;
+        let mut verifying = false;
+        let mut phase_start = Instant::now();
+
+        sleep(initial_delay).await;
+
+        loop {
+            match watcher.poll(&self.client).await? {
+                Some(FlashEvent::Started { size: total }) => {
+                    phase_start = Instant::now();
+                    size = Some(total);
+                    if self.json {
+                        self.print_phase(serde_json::json!({
+                            "phase": "transferring",
+                            "bytes_written": 0,
+                            "size": total,
+                        }));
+                    } else {
+                        bar = Some(self.progress_bar(total));
+                    }
+                }
+                Some(FlashEvent::Transferring { bytes_written }) => {
+                    if let Some(bar) = &bar {
+                        bar.set_position(bytes_written);
+                    }
+                    self.print_phase(serde_json::json!({
+                        "phase": "transferring",
+                        "bytes_written": bytes_written,
+                        "size": size,
+                    }));
+                }
+                Some(FlashEvent::Verifying) => {
+                    if let Some(profiler) = &self.profiler {
+                        profiler.record("write", phase_start.elapsed());
+                    }
+                    phase_start = Instant::now();
+                    if let Some(bar) = &bar {
+                        bar.finish_and_clear();
+                    }
+                    if self.json {
+                        self.print_phase(serde_json::json!({ "phase": "verifying" }));
+                    } else {
+                        let spinner = self.spinner();
+                        spinner.set_message("Verifying checksum...");
+                        bar = Some(spinner);
+                    }
+                    verifying = true;
+                }
+                Some(FlashEvent::Done { recorded, checksum }) => {
+                    if let (Some(profiler), true) = (&self.profiler, verifying) {
+                        profiler.record("verify", phase_start.elapsed());
+                    }
+                    if let Some(bar) = &bar {
+                        bar.finish_and_clear();
+                    }
+                    self.print_flash_summary(recorded, start.elapsed(), checksum);
+                    return Ok(recorded);
                 }
+                None => {}
+            }
 
-                eprintln!("Unexpected response: {:#?}", json);
-                return;
+            sleep(jittered(update_period, self.interval_jitter)).await;
+        }
+    }
+
+    /// Prints a line of the progress of a flash with `--json`, unless
+    /// `--quiet`.
+    fn print_phase(&self, phase: serde_json::Value) {
+        if self.json && !self.quiet {
+            print_json(&phase, self.json_pretty);
+        }
+    }
+
+    /// Prints what was flashed, for the record: the number of bytes, how long
+    /// it took and the outcome of the verification by the BMC.
+    fn print_flash_summary(&self, bytes: Option<u64>, elapsed: Duration, checksum: Option<String>) {
+        let query = self.request.url().query_pairs();
+        let mut expected = None;
+        let mut verification = "passed";
+        for (key, value) in query {
+            match key.as_ref() {
+                "sha256" => expected = Some(value.into_owned()),
+                "skip_crc" => verification = "skipped",
+                _ => {}
             }
-        })
+        }
+
+        if self.json {
+            print_json(
+                &serde_json::json!({
+                    "phase": "done",
+                    "bytes": bytes,
+                    "elapsed_secs": elapsed.as_secs_f64(),
+                    "verification": verification,
+                    "checksum": checksum,
+                    "expected_sha256": expected,
+                }),
+                self.json_pretty,
+            );
+            return;
+        }
+        if self.quiet {
+            return;
+        }
+
+        let bytes = bytes.map_or_else(|| "unknown size".to_string(), |b| HumanBytes(b).to_string());
+        println!(
+            "Done: flashed {bytes} in {}, verification {verification}",
+            HumanDuration(elapsed)
+        );
+        match (checksum, expected) {
+            (Some(checksum), Some(expected)) => {
+                println!("checksum: {checksum} (expected {expected})")
+            }
+            (Some(checksum), None) => println!("checksum: {checksum}"),
+            (None, Some(expected)) => println!("sha256: {expected} (expected)"),
+            (None, None) => {}
+        }
     }
 
     async fn handle_file_upload_v1(
@@ -423,7 +1471,10 @@ impl LegacyHandler {
         file: &mut File,
         file_name: String,
     ) -> anyhow::Result<()> {
-        println!("Warning: large files will very likely to fail to be uploaded in version 1");
+        print_status(
+            self.json,
+            "Warning: large files will very likely to fail to be uploaded in version 1",
+        );
 
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes).await?;
@@ -439,144 +1490,650 @@ impl LegacyHandler {
         Ok(())
     }
 
-    async fn handle_file_upload_v1_1(&self, file: File, file_size: u64) -> anyhow::Result<()> {
-        let req = self.request.clone();
-        let response = req
-            .send(self.client.clone())
-            .await
-            .context("flash request")?;
+    /// Sends `file`, of `file_size` bytes, for `transfer`, which the caller
+    /// started, and follows the flash until it completes.
+    async fn handle_file_upload_v1_1(
+        &self,
+        transfer: Transfer,
+        (file, file_size): (impl AsyncRead + Unpin + Send + Sync + 'static, u64),
+        intervals: (Duration, Duration),
+        print_digest: bool,
+        compress: bool,
+        max_frame_size: usize,
+    ) -> anyhow::Result<Option<u64>> {
+        let start = Instant::now();
+
+        // The upload is only compressed when the BMC advertises that it
+        // accepts gzip encoded request bodies (RFC 7694). The `length` sent
+        // with the flash request stays the size of the image, as that is what
+        // ends up on the node; compression only applies on the wire.
+        let gzip = compress && transfer.accepts_gzip;
+        if compress && !gzip {
+            print_status(
+                self.json,
+                "Warning: the BMC does not accept compressed uploads, sending uncompressed",
+            );
+        }
 
-        if !response.status().is_success() {
-            bail!("could not execute flashing: {}", response.text().await?);
+        let handle = transfer.handle;
+        self.print_handle(handle);
+
+        self.status(format!("started transfer of {}..", HumanBytes(file_size)));
+        let pb = self.progress_bar(file_size);
+        let hasher = Arc::new(Mutex::new(Sha256::new()));
+        let reader = {
+            let hasher = hasher.clone();
+            let file = BufReader::with_capacity(READ_BUFFER_SIZE, file);
+            InspectReader::new(file, move |chunk: &[u8]| {
+                if print_digest {
+                    hasher.lock().expect("hasher lock poisoned").update(chunk);
+                }
+            })
+        };
+        let reader = pb.wrap_async_read(reader);
+
+        if gzip {
+            self.bmc
+                .upload_gzip(&transfer, reader, max_frame_size)
+                .await?;
+        } else {
+            self.bmc
+                .upload(&transfer, reader, file_size, max_frame_size)
+                .await?;
+        }
+
+        let recorded = self.watch_progress(handle, intervals, start).await?;
+
+        if print_digest {
+            let digest = hasher
+                .lock()
+                .expect("hasher lock poisoned")
+                .clone()
+                .finalize();
+            print_status(self.json, format!("sha256: {}", hex::encode(digest)));
         }
 
-        let json: serde_json::Value = response.json().await?;
-        let handle = json["handle"].as_u64().unwrap_or_default();
+        Ok(recorded)
+    }
 
-        println!("started transfer of {}..", HumanBytes(file_size));
-        let pb = build_progress_bar(file_size);
-        let stream = ReaderStream::with_capacity(pb.wrap_async_write(file), MULTIPART_BUFFER_SIZE);
-        let stream_part =
-            reqwest::multipart::Part::stream_with_length(Body::wrap_stream(stream), file_size)
-                .mime_str("application/octet-stream")?;
+    async fn handle_usb(&mut self, args: &UsbArgs) -> anyhow::Result<()> {
+        let Some(mode) = args.mode.usb_mode() else {
+            self.request
+                .url_mut()
+                .query_pairs_mut()
+                .append_pair("opt", "get")
+                .append_pair("type", "usb");
+            self.response_printer = Some(Box::new(print_usb_status));
+            return Ok(());
+        };
+
+        let node = require_node(args.node, self.default_node)?;
+        self.skip_request = true;
+        self.command_bmc()
+            .set_usb_mode(node, mode, args.bmc)
+            .await?;
+        self.print_applied();
+        Ok(())
+    }
 
-        let mut multipart_request = self.request.to_post()?;
-        multipart_request
+    /// The client for the request of the command itself, which carries the
+    /// extra query parameters given after `--`.
+    fn command_bmc(&mut self) -> BmcClient {
+        self.bmc
+            .with_query_params(&std::mem::take(&mut self.query_params))
+    }
+
+    /// Prints the outcome of a setting the BMC applied, as the reply would be
+    /// printed had it gone through `handle_cmd`.
+    fn print_applied(&self) {
+        if self.json {
+            let body = serde_json::json!({ "response": [{ "result": "ok" }] });
+            print_json(&body, self.json_pretty);
+        } else {
+            println!("ok");
+        }
+    }
+
+    async fn handle_power_nodes(&mut self, args: &PowerArgs) -> anyhow::Result<()> {
+        ensure!(
+            args.hold.is_none() || args.cmd == PowerCmd::Reset,
+            TpiError::Usage("`--hold` can only be used with `reset`".into())
+        );
+        ensure!(
+            args.delay.is_none() || args.cmd == PowerCmd::Cycle,
+            TpiError::Usage("`--delay` can only be used with `cycle`".into())
+        );
+        ensure!(
+            args.stagger.is_none() || args.cmd == PowerCmd::On,
+            TpiError::Usage("`--stagger` can only be used with `on`".into())
+        );
+        ensure!(
+            args.file.is_none(),
+            TpiError::Usage("a file can only be passed to `sequence`".into())
+        );
+        ensure!(
+            !args.exit_code || args.cmd == PowerCmd::Status,
+            TpiError::Usage("`--exit-code` can only be used with `status`".into())
+        );
+
+        if args.exit_code || (args.cmd == PowerCmd::Status && args.node.is_some()) {
+            let node = require_node(args.node, self.default_node)?;
+            return self.power_status_exit_code(node).await;
+        }
+
+        if let Some(hold) = args.hold {
+            let node = require_node(args.node, self.default_node)?;
+            return self.hold_reset(node, Duration::from_millis(hold)).await;
+        }
+
+        if matches!(args.cmd, PowerCmd::On | PowerCmd::Off | PowerCmd::Cycle)
+            && args.node.is_none()
+            && !args.all
+        {
+            self.confirm_all_nodes(&args.cmd)?;
+        }
+
+        if args.cmd == PowerCmd::Cycle {
+            let delay = args.delay.unwrap_or(DEFAULT_CYCLE_DELAY);
+            return self.power_cycle(args.node, delay).await;
+        }
+
+        if let Some(stagger) = args.stagger {
+            return self
+                .staggered_power_on(Duration::from_millis(stagger))
+                .await;
+        }
+
+        if args.cmd == PowerCmd::Status {
+            self.request
+                .url_mut()
+                .query_pairs_mut()
+                .append_pair("opt", "get")
+                .append_pair("type", "power");
+            let base = self.node_base;
+            self.response_printer = Some(Box::new(move |map| {
+                print_power_status_nodes(map, base, None)
+            }));
+            return Ok(());
+        }
+
+        self.skip_request = true;
+        if args.cmd == PowerCmd::Reset {
+            let node = require_node(args.node, self.default_node)?;
+            self.command_bmc().reset(node).await?;
+        } else {
+            let on = args.cmd == PowerCmd::On;
+            let bmc = self.command_bmc();
+            match args.node {
+                Some(node) => bmc.set_power(node, on).await?,
+                None => bmc.set_power_all(on).await?,
+            }
+        }
+        self.print_applied();
+        Ok(())
+    }
+
+    /// Runs the steps of a power sequence file one after another, sending
+    /// each step as its own request.
+    async fn handle_power_sequence(&mut self, args: &PowerArgs) -> anyhow::Result<()> {
+        self.skip_request = true;
+
+        let path = args.file.as_deref().expect("clap requires a file");
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        let steps = parse_power_sequence(&text, self.node_base)?;
+
+        let request = self.request.clone();
+        for step in steps {
+            let action = step.cmd.to_possible_value().expect("no skipped variants");
+            self.status(format!(
+                "{} node {}",
+                action.get_name(),
+                self.shown(step.node)
+            ));
+
+            self.request = request.clone();
+            self.handle_power_nodes(&PowerArgs {
+                cmd: step.cmd,
+                node: Some(step.node),
+                all: false,
+                hold: None,
+                delay: None,
+                stagger: None,
+                file: None,
+                exit_code: false,
+                wait_ssh: None,
+                wait_timeout: None,
+            })
+            .await?;
+
+            let step_request = std::mem::replace(&mut self.request, request.clone());
+            let response = self.query(step_request).await?;
+            if self.json {
+                print_json(&response, self.json_pretty);
+            } else if let Some(printer) = self.response_printer.take() {
+                printer(&response)?;
+            }
+
+            if !step.delay.is_zero() {
+                sleep(step.delay).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Powers nodes on, then waits until the address given by `--wait-ssh`
+    /// accepts TCP connections.
+    async fn handle_power_on_and_wait(&mut self, args: &PowerArgs) -> anyhow::Result<()> {
+        ensure!(
+            args.cmd == PowerCmd::On,
+            TpiError::Usage("`--wait-ssh` can only be used with `on`".into())
+        );
+        let endpoint = ssh_endpoint(args.wait_ssh.as_deref().expect("checked by caller"));
+
+        self.handle_power_nodes(args).await?;
+        self.skip_request = true;
+        let response = self.query(self.request.clone()).await?;
+        if self.json {
+            print_json(&response, self.json_pretty);
+        } else {
+            result_printer(&response)?;
+        }
+
+        let limit = args
+            .wait_timeout
+            .map_or(WAIT_TCP_TIMEOUT, Duration::from_secs);
+        let spinner = self.spinner();
+        spinner.set_message(format!("Waiting for {endpoint}..."));
+        let start = Instant::now();
+        loop {
+            let remaining = limit.saturating_sub(start.elapsed());
+            if let Ok(Ok(_)) = timeout(remaining, TcpStream::connect(&endpoint)).await {
+                break;
+            }
+            if start.elapsed() >= limit {
+                spinner.finish_and_clear();
+                bail!(
+                    "{endpoint} did not become reachable within {}s",
+                    limit.as_secs()
+                );
+            }
+            sleep(WAIT_TCP_RETRY.min(limit.saturating_sub(start.elapsed()))).await;
+        }
+        spinner.finish_and_clear();
+
+        self.status(format!(
+            "{endpoint} is reachable after {}s",
+            start.elapsed().as_secs()
+        ));
+        Ok(())
+    }
+
+    /// Prints the power status and reflects the state of `node` in the exit
+    /// code.
+    async fn power_status_exit_code(&mut self, node: u8) -> anyhow::Result<()> {
+        self.skip_request = true;
+
+        let mut request = self.request.clone();
+        request
             .url_mut()
-            .path_segments_mut()
-            .unwrap()
-            .push("upload")
-            .push(&handle.to_string());
+            .query_pairs_mut()
+            .append_pair("opt", "get")
+            .append_pair("type", "power");
+        let response = self.query(request).await?;
+        let on = parse_result_object::<PowerStatus>(&response)?
+            .is_on(node)
+            .with_context(|| format!("API error: no power state for node {}", self.shown(node)))?;
+
+        if self.json {
+            let state = if on { "1" } else { "0" };
+            print_json(
+                &serde_json::json!({ "result": { format!("node{node}"): state } }),
+                self.json_pretty,
+            );
+        } else {
+            print_power_status_nodes(&response, self.node_base, Some(node))?;
+        }
+
+        if !on {
+            self.exit_code = ExitCode::from(EXIT_NODE_OFF);
+        }
+        Ok(())
+    }
+
+    /// The BMC has no notion of a reset hold time. Instead the node is powered
+    /// off, and on again after `hold`; the power-on request carries the extra
+    /// query parameters of the command.
+    async fn hold_reset(&mut self, node: u8, hold: Duration) -> anyhow::Result<()> {
+        self.skip_request = true;
+        self.set_node_power(node, false).await?;
+
+        sleep(hold).await;
+
+        self.command_bmc().set_power(node, true).await?;
+        self.print_applied();
+        Ok(())
+    }
+
+    /// Powers `node`, or all nodes, off, and on again after `delay`. The off
+    /// request has to succeed before anything else is sent; the on request
+    /// carries the extra query parameters of the command.
+    async fn power_cycle(&mut self, node: Option<u8>, delay: Duration) -> anyhow::Result<()> {
+        let nodes = node.map_or(vec![1, 2, 3, 4], |node| vec![node]);
+        let target = match node {
+            Some(node) => format!("node {}", self.shown(node)),
+            None => "all nodes".to_string(),
+        };
+
+        self.skip_request = true;
+        self.status(format!("powering off {target}"));
+        for &node in &nodes {
+            self.set_node_power(node, false).await?;
+        }
+
+        self.status(format!("waiting {}", HumanDuration(delay)));
+        sleep(delay).await;
+
+        self.status(format!("powering on {target}"));
+        let bmc = self.command_bmc();
+        match node {
+            Some(node) => bmc.set_power(node, true).await?,
+            None => bmc.set_power_all(true).await?,
+        }
+        self.print_applied();
+        Ok(())
+    }
+
+    /// Powers all nodes on one after another, `stagger` apart. The request
+    /// for the last node carries the extra query parameters of the command.
+    async fn staggered_power_on(&mut self, stagger: Duration) -> anyhow::Result<()> {
+        self.skip_request = true;
+        for node in 1..4 {
+            self.status(format!("powering on node {}", self.shown(node)));
+            self.set_node_power(node, true).await?;
+            sleep(stagger).await;
+        }
+
+        self.status(format!("powering on node {}", self.shown(4)));
+        self.command_bmc().set_power(4, true).await?;
+        self.print_applied();
+        Ok(())
+    }
 
-        let form = reqwest::multipart::Form::new().part("file", stream_part);
-        multipart_request.set_multipart(form);
-        multipart_request.send(self.client.clone()).await?;
+    /// Powers a single node on or off as an intermediate step of another
+    /// command, failing unless the BMC reports success.
+    async fn set_node_power(&self, node: u8, on: bool) -> anyhow::Result<()> {
+        self.bmc.set_power(node, on).await.with_context(|| {
+            format!(
+                "could not power {} node {}",
+                if on { "on" } else { "off" },
+                self.shown(node)
+            )
+        })
+    }
 
-        let progress_watcher = self.create_progress_watching_thread(handle);
-        progress_watcher.await.expect("failed to wait for thread");
+    /// Guards against accidentally powering all nodes on or off.
+    fn confirm_all_nodes(&self, cmd: &PowerCmd) -> anyhow::Result<()> {
+        ensure!(
+            !self.batch,
+            TpiError::Usage("specify either `--node` or `--all`".into())
+        );
+
+        let question = match cmd {
+            PowerCmd::On => "Power on all nodes? [y/N]",
+            PowerCmd::Cycle => "Power cycle all nodes? [y/N]",
+            _ => "Power off all nodes? [y/N]",
+        };
 
+        ensure!(
+            prompt::confirm(question)?,
+            TpiError::Usage("aborted, use `--node` or `--all`".into())
+        );
         Ok(())
     }
 
-    fn handle_usb(&mut self, args: &UsbArgs) -> anyhow::Result<()> {
+    async fn handle_cooling(&mut self, args: &CoolingArgs) -> anyhow::Result<()> {
+        if args.all {
+            ensure!(
+                args.cmd == CoolingCmd::Set,
+                TpiError::Usage("`--all` can only be used with the set command".into())
+            );
+            let speed = args
+                .all_speed
+                .expect("clap requires `--speed` with `--all`");
+            return self.set_all_cooling_devices(speed, args.force).await;
+        }
+
+        if args.cmd == CoolingCmd::Curve {
+            return self.cooling_curve(args).await;
+        }
+
+        if let (CoolingCmd::Set, Some(device)) = (&args.cmd, &args.device) {
+            if !args.skip_device_check {
+                let found = self.find_cooling_device(device).await?;
+                if let (Some(speed), false) = (args.speed, args.force) {
+                    ensure_cooling_speed(&found, speed)?;
+                }
+            }
+        }
+
         let mut serializer = self.request.url_mut().query_pairs_mut();
-        if args.mode == UsbCmd::Status {
-            serializer
-                .append_pair("opt", "get")
-                .append_pair("type", "usb");
-            self.response_printer = Some(print_usb_status);
-            return Ok(());
+        match args.cmd {
+            CoolingCmd::Status => {
+                serializer
+                    .append_pair("opt", "get")
+                    .append_pair("type", "cooling");
+            }
+            CoolingCmd::List => {
+                serializer
+                    .append_pair("opt", "get")
+                    .append_pair("type", "cooling");
+                self.response_printer = Some(Box::new(cooling_list_printer));
+                let pretty = self.json_pretty;
+                self.json_printer =
+                    Some(Box::new(move |map| cooling_list_json_printer(map, pretty)));
+                return Ok(());
+            }
+            CoolingCmd::Curve => unreachable!("handled above"),
+            CoolingCmd::Set => match (args.device.as_ref(), args.speed) {
+                (Some(device), Some(speed)) => {
+                    serializer
+                        .append_pair("opt", "set")
+                        .append_pair("type", "cooling")
+                        .append_pair("device", device)
+                        .append_pair("speed", &speed.to_string());
+                }
+                _ => {
+                    bail!(TpiError::Usage(
+                        "Device and speed arguments are required for the set command".into()
+                    ));
+                }
+            },
         }
 
-        let Some(node) = args.node else {
-            bail!("`--node` argument missing")
+        self.response_printer = Some(Box::new(cooling_printer));
+
+        Ok(())
+    }
+
+    async fn cooling_status(&self) -> anyhow::Result<Vec<CoolingDevice>> {
+        let mut request = self.request.clone();
+        request
+            .url_mut()
+            .query_pairs_mut()
+            .append_pair("opt", "get")
+            .append_pair("type", "cooling");
+        parse_result(&self.query(request).await?)
+    }
+
+    async fn set_cooling_speed(&self, device: &str, speed: u32) -> anyhow::Result<()> {
+        let mut request = self.request.clone();
+        request
+            .url_mut()
+            .query_pairs_mut()
+            .append_pair("opt", "set")
+            .append_pair("type", "cooling")
+            .append_pair("device", device)
+            .append_pair("speed", &speed.to_string());
+        self.query(request).await?;
+        Ok(())
+    }
+
+    /// Reads the temperature of the board, `None` if the BMC does not report
+    /// one.
+    async fn board_temperature(&self) -> anyhow::Result<Option<f64>> {
+        let mut request = self.request.clone();
+        request
+            .url_mut()
+            .query_pairs_mut()
+            .append_pair("opt", "get")
+            .append_pair("type", "other");
+        let info: Info = parse_result_object(&self.query(request).await?)?;
+        Ok(info_temperature(&info))
+    }
+
+    /// Sets the speed of the cooling devices from the temperature of the
+    /// board every `--period`, until Ctrl-C. The speed is only set when it
+    /// changes, and never above the `max_speed` of the device.
+    async fn cooling_curve(&mut self, args: &CoolingArgs) -> anyhow::Result<()> {
+        self.skip_request = true;
+        ensure!(
+            !args.points.is_empty(),
+            TpiError::Usage("`curve` requires `--points`, e.g. `--points 40:0,60:2,75:4`".into())
+        );
+        let mut points = args.points.clone();
+        points.sort_by(|a, b| a.temp.total_cmp(&b.temp));
+
+        // The devices and their maximum speed, if known.
+        let devices: Vec<(String, Option<u64>)> = match &args.device {
+            Some(device) if args.skip_device_check => vec![(device.clone(), None)],
+            Some(device) => {
+                let found = self.find_cooling_device(device).await?;
+                vec![(found.device, Some(found.max_speed))]
+            }
+            None => {
+                let status = self.cooling_status().await?;
+                ensure!(!status.is_empty(), "the BMC reports no cooling devices");
+                status
+                    .into_iter()
+                    .map(|d| (d.device, Some(d.max_speed)))
+                    .collect()
+            }
         };
 
-        serializer
-            .append_pair("opt", "set")
-            .append_pair("type", "usb")
-            .append_pair("node", &(node - 1).to_string());
-
-        let mut mode = match args.mode {
-            UsbCmd::Host => 0,
-            UsbCmd::Device => 1,
-            UsbCmd::Flash => 2,
-            UsbCmd::Status => panic!("cannot reach here"),
-        };
+        let cancel = cancel_on_ctrl_c();
+        let mut applied: Vec<Option<u32>> = vec![None; devices.len()];
+        while !cancel.is_cancelled() {
+            match self.board_temperature().await {
+                Ok(Some(temp)) => {
+                    let target = curve_speed(&points, temp);
+                    for ((device, max_speed), applied) in devices.iter().zip(&mut applied) {
+                        let speed = max_speed
+                            .and_then(|max| u32::try_from(max).ok())
+                            .map_or(target, |max| target.min(max));
+                        if *applied == Some(speed) {
+                            continue;
+                        }
+                        // Retried on the next poll, the fan keeps its speed.
+                        if let Err(e) = self.set_cooling_speed(device, speed).await {
+                            print_status(
+                                self.json,
+                                format!("Warning: cannot set the speed of {device}: {e:#}"),
+                            );
+                            *applied = None;
+                            continue;
+                        }
+                        self.status(format!("{temp:.1}°C: {device} set to {speed}"));
+                        *applied = Some(speed);
+                    }
+                }
+                Ok(None) => print_status(
+                    self.json,
+                    "Warning: the BMC reports no temperature, the fan speed is left unchanged",
+                ),
+                Err(e) => print_status(
+                    self.json,
+                    format!(
+                        "Warning: cannot read the temperature: {e:#}, the fan speed is left \
+                         unchanged"
+                    ),
+                ),
+            }
 
-        mode |= u8::from(args.bmc) << 2;
-        serializer.append_pair("mode", &mode.to_string());
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                _ = sleep(jittered(args.period, self.interval_jitter)) => {}
+            }
+        }
 
-        self.response_printer = Some(result_printer);
+        self.status("stopped, the cooling devices keep their last speed");
         Ok(())
     }
 
-    fn handle_power_nodes(&mut self, args: &PowerArgs) -> anyhow::Result<()> {
-        let mut serializer = self.request.url_mut().query_pairs_mut();
-        if args.cmd == PowerCmd::Status {
-            serializer
-                .append_pair("opt", "get")
-                .append_pair("type", "power");
-            self.response_printer = Some(print_power_status_nodes);
-            return Ok(());
-        } else if args.cmd == PowerCmd::Reset {
-            ensure!(args.node.is_some(), "`--node` argument must be set.");
-            serializer
-                .append_pair("opt", "set")
-                .append_pair("type", "reset")
-                .append_pair("node", &(args.node.unwrap() - 1).to_string());
-            self.response_printer = Some(result_printer);
-            return Ok(());
-        }
-
-        serializer
-            .append_pair("opt", "set")
-            .append_pair("type", "power");
+    /// Returns the cooling device named `device`, failing when the BMC does not
+    /// list it.
+    async fn find_cooling_device(&self, device: &str) -> anyhow::Result<CoolingDevice> {
+        select_cooling_device(self.cooling_status().await?, device)
+    }
 
-        let on_bit = if args.cmd == PowerCmd::On { "1" } else { "0" };
+    async fn set_all_cooling_devices(&mut self, speed: u32, force: bool) -> anyhow::Result<()> {
+        self.skip_request = true;
 
-        if let Some(node) = args.node {
-            serializer.append_pair(&format!("node{}", node), on_bit);
-        } else {
-            serializer.append_pair("node1", on_bit);
-            serializer.append_pair("node2", on_bit);
-            serializer.append_pair("node3", on_bit);
-            serializer.append_pair("node4", on_bit);
+        let status = self.cooling_status().await?;
+        ensure!(!status.is_empty(), "the BMC reports no cooling devices");
+        if !force {
+            status
+                .iter()
+                .try_for_each(|device| ensure_cooling_speed(device, speed))?;
         }
-        self.response_printer = Some(result_printer);
-        Ok(())
-    }
+        let devices: Vec<_> = status.into_iter().map(|d| d.device).collect();
 
-    async fn handle_cooling(&mut self, args: &CoolingArgs) -> anyhow::Result<()> {
-        let mut serializer = self.request.url_mut().query_pairs_mut();
-        match args.cmd {
-            CoolingCmd::Status => {
-                serializer
-                    .append_pair("opt", "get")
-                    .append_pair("type", "cooling");
+        let mut failed = 0;
+        let mut outcomes = Vec::new();
+        for device in &devices {
+            let mut request = self.request.clone();
+            request
+                .url_mut()
+                .query_pairs_mut()
+                .append_pair("opt", "set")
+                .append_pair("type", "cooling")
+                .append_pair("device", device)
+                .append_pair("speed", &speed.to_string());
+
+            // A failing device does not stop the others from being set.
+            let outcome = match self.query(request).await {
+                Ok(response) => match response.get("result") {
+                    Some(result) => Ok(display_value(result)),
+                    None => Err(response.to_string()),
+                },
+                Err(e) => Err(format!("{e:#}")),
+            };
+            match &outcome {
+                Ok(result) if !self.json => println!("{device}: {result}"),
+                Err(e) if !self.json => println!("{device}: failed: {e}"),
+                _ => {}
             }
-            CoolingCmd::Set => match (args.device.as_ref(), args.speed) {
-                (Some(device), Some(speed)) => {
-                    serializer
-                        .append_pair("opt", "set")
-                        .append_pair("type", "cooling")
-                        .append_pair("device", device)
-                        .append_pair("speed", &speed.to_string());
-                }
-                _ => {
-                    return Err(anyhow::anyhow!(
-                        "Device and speed arguments are required for the set command"
-                    ));
-                }
-            },
+            failed += usize::from(outcome.is_err());
+            outcomes.push(match outcome {
+                Ok(result) => serde_json::json!({ "device": device, "result": result }),
+                Err(e) => serde_json::json!({ "device": device, "error": e }),
+            });
         }
 
-        self.response_printer = Some(cooling_printer);
+        if self.json {
+            print_json(&serde_json::Value::Array(outcomes), self.json_pretty);
+        }
 
+        ensure!(
+            failed == 0,
+            "{failed} of {} cooling devices could not be set",
+            devices.len()
+        );
         Ok(())
     }
 
     async fn handle_advanced(&mut self, args: &AdvancedArgs) -> anyhow::Result<()> {
+        let node = require_node(args.node, self.default_node)?;
         match args.mode {
             crate::cli::ModeCmd::Normal => {
                 self.request
@@ -584,17 +2141,30 @@ impl LegacyHandler {
                     .query_pairs_mut()
                     .append_pair("opt", "set")
                     .append_pair("type", "clear_usb_boot")
-                    .append_pair("node", &(args.node - 1).to_string());
+                    .append_pair("node", &api_node(node));
                 let response = self.request.clone().send(self.client.clone()).await?;
 
                 if !response.status().is_success() {
-                    bail!("could not execute Normal mode: {}", response.text().await?);
+                    bail!(TpiError::Bmc(format!(
+                        "could not execute Normal mode: {}",
+                        response.text().await?
+                    )));
                 }
 
-                return self.handle_power_nodes(&PowerArgs {
-                    cmd: PowerCmd::Reset,
-                    node: Some(args.node),
-                });
+                return self
+                    .handle_power_nodes(&PowerArgs {
+                        cmd: PowerCmd::Reset,
+                        node: Some(node),
+                        all: false,
+                        hold: None,
+                        delay: None,
+                        stagger: None,
+                        file: None,
+                        exit_code: false,
+                        wait_ssh: None,
+                        wait_timeout: None,
+                    })
+                    .await;
             }
             crate::cli::ModeCmd::Msd => {
                 self.request
@@ -602,10 +2172,10 @@ impl LegacyHandler {
                     .query_pairs_mut()
                     .append_pair("opt", "set")
                     .append_pair("type", "node_to_msd")
-                    .append_pair("node", &(args.node - 1).to_string());
+                    .append_pair("node", &api_node(node));
             }
         }
-        self.response_printer = Some(result_printer);
+        self.response_printer = Some(Box::new(result_printer));
 
         Ok(())
     }
@@ -624,28 +2194,26 @@ impl LegacyHandler {
                     println!("{:#?}", board_info)
                 }
             }
-            GetSet::Set => {
-                if let Ok(ver) = std::env::var("tpi_hw_version") {
-                    let val = if ver.to_lowercase().starts_with("0x") {
-                        u16::from_str_radix(&ver[2..], 16)?
+            GetSet::Set if args.dry_run => {
+                let mut changed = board_info.clone();
+                apply_eeprom_changes(&mut changed)?;
+                // round trip to obtain the crc that would be written
+                let changed = BoardInfo::from_bytes(changed.to_bytes())?;
+
+                for attribute in crate::cli::BoardInfoAttribute::value_variants() {
+                    let before = board_info.value_of(attribute);
+                    let after = changed.value_of(attribute);
+                    let name = attribute.to_possible_value().expect("no skipped variants");
+                    if before == after {
+                        println!(" {:<15} {}", name.get_name(), before);
                     } else {
-                        ver.parse::<u16>()?
-                    };
-                    board_info.hw_version(val);
-                }
-                if let Ok(dt) = std::env::var("tpi_factory_date") {
-                    board_info.factory_date(dt.parse::<u16>()?);
-                }
-                if let Ok(ser) = std::env::var("tpi_factory_serial") {
-                    board_info.factory_serial(ser);
-                }
-                if let Ok(name) = std::env::var("tpi_product_name") {
-                    board_info.product_name(name);
-                }
-                if let Ok(mac) = std::env::var("tpi_mac") {
-                    board_info.mac(mac).context("parsing mac")?;
+                        println!("*{:<15} {} -> {}", name.get_name(), before, after);
+                    }
                 }
-
+                println!("dry run, nothing written");
+            }
+            GetSet::Set => {
+                apply_eeprom_changes(&mut board_info)?;
                 board_info.write_back()?;
             }
         }
@@ -653,60 +2221,556 @@ impl LegacyHandler {
     }
 }
 
-fn print_power_status_nodes(map: &serde_json::Value) -> anyhow::Result<()> {
-    let results = map
-        .get("result")
-        .context("API error")?
-        .as_array()
-        .context("API error")?[0]
-        .as_object()
-        .context("response parse error")?;
+/// Applies the EEPROM changes passed through `tpi_*` environment variables.
+#[cfg(feature = "localhost")]
+fn apply_eeprom_changes(board_info: &mut crate::board_info::BoardInfo) -> anyhow::Result<()> {
+    if let Ok(ver) = std::env::var("tpi_hw_version") {
+        let val = if ver.to_lowercase().starts_with("0x") {
+            u16::from_str_radix(&ver[2..], 16)?
+        } else {
+            ver.parse::<u16>()?
+        };
+        board_info.hw_version(val);
+    }
+    if let Ok(dt) = std::env::var("tpi_factory_date") {
+        // Either the stored number of days, or a calendar date.
+        match dt.parse::<chrono::NaiveDate>() {
+            Ok(date) => board_info.factory_date_from(date)?,
+            Err(_) => board_info.factory_date(dt.parse::<u16>()?),
+        }
+    }
+    if let Ok(ser) = std::env::var("tpi_factory_serial") {
+        board_info.factory_serial(ser);
+    }
+    if let Ok(name) = std::env::var("tpi_product_name") {
+        board_info.product_name(name);
+    }
+    if let Ok(mac) = std::env::var("tpi_mac") {
+        board_info.mac(mac).context("parsing mac")?;
+    }
+    Ok(())
+}
 
-    for (key, value) in results {
-        let number = value.as_str().context("API error")?.parse::<u8>()?;
-        let status = if number == 1 { "On" } else { "off" };
-        println!("{}: {}", key, status);
+fn busy_message(retry_after: Option<u64>, body: &str) -> String {
+    let reason = if body.to_lowercase().contains("flash") {
+        " (flashing in progress)"
+    } else {
+        ""
+    };
+
+    match retry_after {
+        Some(secs) => format!("BMC is busy{reason}, retry in {secs}s"),
+        None => format!("BMC is busy{reason}, retry later"),
+    }
+}
+
+/// Adds the parameters that read the UART of `node`, or write `cmd` to it.
+fn append_uart_query(request: &mut Request, args: &UartArgs, node: u8, cmd: Option<&str>) {
+    let mut serializer = request.url_mut().query_pairs_mut();
+    match args.action {
+        GetSet::Get => serializer
+            .append_pair("opt", "get")
+            .append_pair("type", "uart")
+            .append_pair("node", &api_node(node)),
+        GetSet::Set => serializer
+            .append_pair("opt", "set")
+            .append_pair("type", "uart")
+            .append_pair("node", &api_node(node))
+            .append_pair("cmd", cmd.unwrap_or_default()),
+    };
+}
+
+/// Largest part of `--cmd-file` sent in one request. The input ends up in the
+/// URL, whose length HTTP servers limit.
+const UART_CHUNK_SIZE: usize = 1024;
+
+/// Splits `input` into chunks of at most `UART_CHUNK_SIZE` bytes, without
+/// splitting a character.
+fn uart_chunks(mut input: &str) -> impl Iterator<Item = &str> {
+    std::iter::from_fn(move || {
+        if input.is_empty() {
+            return None;
+        }
+        let mut end = input.len().min(UART_CHUNK_SIZE);
+        while !input.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, rest) = input.split_at(end);
+        input = rest;
+        Some(chunk)
+    })
+}
+
+/// Overlaps shorter than this are not skipped by `new_uart_output`, they are
+/// more likely repeated output than output that was seen before.
+const MIN_UART_OVERLAP: usize = 16;
+
+/// Returns the part of `current` that `previous`, the output of the previous
+/// poll, did not contain yet. The BMC returns a window of its UART buffer, which
+/// can still contain output that was already printed: the longest end of
+/// `previous` that `current` starts with is skipped.
+fn new_uart_output<'a>(previous: &str, current: &'a str) -> &'a str {
+    if let Some(added) = current.strip_prefix(previous) {
+        return added;
+    }
+
+    let (previous, bytes) = (previous.as_bytes(), current.as_bytes());
+    let overlap = (0..previous.len())
+        .map(|start| &previous[start..])
+        .take_while(|tail| tail.len() >= MIN_UART_OVERLAP)
+        .find(|tail| bytes.starts_with(tail))
+        .map_or(0, <[u8]>::len);
+    current.get(overlap..).unwrap_or(current)
+}
+
+struct PowerStep {
+    cmd: PowerCmd,
+    node: u8,
+    delay: Duration,
+}
+
+/// Parses the lines of a power sequence file, see `PowerArgs::file`.
+fn parse_power_sequence(text: &str, node_base: u8) -> anyhow::Result<Vec<PowerStep>> {
+    let mut steps = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let step = parse_power_step(line, node_base)
+            .map_err(|e| TpiError::Usage(format!("line {}: {e}", idx + 1)))?;
+        steps.push(step);
+    }
+
+    ensure!(
+        !steps.is_empty(),
+        TpiError::Usage("power sequence contains no steps".into())
+    );
+    Ok(steps)
+}
+
+fn parse_power_step(line: &str, node_base: u8) -> Result<PowerStep, String> {
+    let mut fields = line.split_whitespace();
+
+    let cmd = match fields.next().map(|f| PowerCmd::from_str(f, true)) {
+        Some(Ok(cmd @ (PowerCmd::On | PowerCmd::Off | PowerCmd::Reset))) => cmd,
+        _ => return Err(format!("expected `on`, `off` or `reset` in `{line}`")),
+    };
+
+    let node = fields
+        .next()
+        .and_then(|f| f.parse::<u8>().ok())
+        .and_then(|n| node_from_base(n, node_base))
+        .ok_or_else(|| {
+            format!(
+                "expected a node {}-{} in `{line}`",
+                node_to_base(1, node_base),
+                node_to_base(4, node_base)
+            )
+        })?;
+
+    let delay = match fields.next() {
+        Some(delay) => parse_delay(delay).ok_or_else(|| format!("invalid delay `{delay}`"))?,
+        None => Duration::ZERO,
+    };
+
+    if fields.next().is_some() {
+        return Err(format!("unexpected trailing fields in `{line}`"));
+    }
+
+    Ok(PowerStep { cmd, node, delay })
+}
+
+/// Completes `target` of `--wait-ssh` with the SSH port if it has none.
+fn ssh_endpoint(target: &str) -> String {
+    if target.parse::<SocketAddr>().is_ok() {
+        return target.to_string();
+    }
+    if let Ok(ip) = target.parse::<IpAddr>() {
+        return SocketAddr::new(ip, 22).to_string();
+    }
+    match target.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => target.to_string(),
+        _ => format!("{target}:22"),
+    }
+}
+
+/// Compares the number of bytes the BMC recorded for an upload with the
+/// number that was sent. Without `strict`, a difference is only reported.
+fn check_recorded_length(
+    json: bool,
+    sent: u64,
+    recorded: Option<u64>,
+    strict: bool,
+) -> anyhow::Result<()> {
+    let Some(recorded) = recorded.filter(|&recorded| recorded != sent) else {
+        return Ok(());
+    };
+
+    let msg = format!("uploaded {sent} bytes but BMC recorded {recorded}");
+    ensure!(!strict, TpiError::Integrity(msg));
+    print_status(json, format!("Warning: {msg}"));
+    Ok(())
+}
+
+/// Returns the parameters that start flashing `file_name` to `node`.
+fn flash_params(
+    args: &FlashArgs,
+    file_name: &str,
+    file_size: u64,
+    node: u8,
+    sha256: Option<&str>,
+) -> FlashParams {
+    FlashParams {
+        node,
+        file_name: file_name.to_string(),
+        size: file_size,
+        sha256: sha256.map(str::to_string),
+        skip_crc: args.skip_crc,
+        device: args.target.clone(),
+    }
+}
+
+/// Reads the password given by `--password-file` or `--password-stdin`,
+/// `None` if neither was passed.
+fn read_password(args: &Cli) -> anyhow::Result<Option<String>> {
+    let text = if let Some(path) = &args.password_file {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read password from {}", path.display()))?
+    } else if args.password_stdin {
+        let mut line = String::new();
+        std::io::stdin()
+            .read_line(&mut line)
+            .context("cannot read password from stdin")?;
+        line
+    } else {
+        return Ok(None);
+    };
+
+    let password = text.lines().next().unwrap_or_default();
+    ensure!(
+        !password.is_empty(),
+        TpiError::Usage("the password read from file or stdin is empty".into())
+    );
+    Ok(Some(password.to_string()))
+}
+
+/// Completes the credentials passed on the command line, or through the
+/// environment, from the netrc entry for the host. A netrc entry for a
+/// different user than `--user` is ignored.
+fn netrc_fallback(args: &Cli, password: Option<String>) -> (Option<String>, Option<String>) {
+    let creds = (args.user.clone(), password);
+    if let (Some(_), Some(_)) = creds {
+        return creds;
+    }
+
+    let host = args.host.as_deref().expect("host has a default set");
+    match (creds, crate::netrc::credentials(host)) {
+        ((None, password), Some((login, netrc_password))) => {
+            (Some(login), password.or(Some(netrc_password)))
+        }
+        ((Some(user), None), Some((login, netrc_password))) if user == login => {
+            (Some(user), Some(netrc_password))
+        }
+        (creds, _) => creds,
+    }
+}
+
+/// Returns the explicitly passed node, falling back on `--default-node`.
+fn require_node(node: Option<u8>, default_node: Option<u8>) -> anyhow::Result<u8> {
+    node.or(default_node)
+        .ok_or_else(|| TpiError::Usage("`--node` argument missing".into()).into())
+}
+
+/// Block devices, e.g. a cloned microSD card, can be flashed like image files;
+/// `open_file` gets their size by seeking to the end.
+#[cfg(unix)]
+fn is_block_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::metadata(path).is_ok_and(|m| m.file_type().is_block_device())
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_path: &Path) -> bool {
+    false
+}
+
+/// Returns the URL an image is to be downloaded from, if `--image-path` is an
+/// `http` or `https` URL rather than a path.
+fn image_url(path: &Path) -> Option<Url> {
+    let path = path.to_str()?;
+    if !path.starts_with("http://") && !path.starts_with("https://") {
+        return None;
+    }
+    Url::parse(path).ok()
+}
+
+/// Starts downloading the image at `url`. Returns the response, whose body is
+/// the image, its file name and its size.
+async fn open_url(url: &Url) -> anyhow::Result<(reqwest::Response, String, u64)> {
+    // Not the client of the BMC: the image server is a different host, which
+    // may not speak https. Compression is turned off, as the `length` sent to
+    // the BMC is the size of the image.
+    let client = Client::builder()
+        .user_agent(client::user_agent())
+        .no_gzip()
+        .build()?;
+    let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("cannot download {url}"))?;
+
+    let size = response.content_length().filter(|size| *size > 0);
+    let size = size.ok_or_else(|| {
+        TpiError::Usage(format!(
+            "{url} does not report the size of the image, download it first"
+        ))
+    })?;
+
+    let file_name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("image")
+        .to_string();
+    Ok((response, file_name, size))
+}
+
+/// Turns the body of `response` into a reader, so that it can be uploaded like
+/// a file.
+fn response_reader(
+    mut response: reqwest::Response,
+) -> impl AsyncRead + Unpin + Send + Sync + 'static {
+    let (mut writer, reader) = tokio::io::duplex(READ_BUFFER_SIZE);
+    tokio::spawn(async move {
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    if writer.write_all(&chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                // The upload then ends short of its length, which fails it.
+                Err(e) => {
+                    eprintln!("Error: downloading the image failed: {e}");
+                    break;
+                }
+            }
+        }
+    });
+    reader
+}
+
+/// Fails with a friendly message when `path` does not point to a readable
+/// file, before any request is set up.
+fn ensure_readable_file(path: &Path) -> anyhow::Result<()> {
+    let metadata = match std::fs::metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!(TpiError::Usage(format!(
+                "image not found: {}",
+                path.display()
+            )))
+        }
+        res => res.with_context(|| format!("cannot access {}", path.display()))?,
+    };
+
+    ensure!(
+        !metadata.is_dir(),
+        TpiError::Usage(format!(
+            "{} is a directory, expected a file",
+            path.display()
+        ))
+    );
+
+    std::fs::File::open(path).with_context(|| format!("cannot read {}", path.display()))?;
+    Ok(())
+}
+
+/// Collects the images in `dir` that are named after a node, e.g.
+/// `node2.img`, sorted by node.
+///
+/// Images are named by node in `--node-base` numbering, the returned nodes
+/// are 1-based.
+fn find_node_images(dir: &Path, node_base: u8) -> anyhow::Result<Vec<(u8, PathBuf)>> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("cannot read {}", dir.display()))?;
+
+    let mut images = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let stem = name.split('.').next().unwrap_or_default().to_lowercase();
+        let node = stem
+            .strip_prefix("node")
+            .and_then(|n| n.parse::<u8>().ok())
+            .and_then(|n| node_from_base(n, node_base));
+
+        if let Some(node) = node {
+            ensure!(
+                images.iter().all(|(n, _)| *n != node),
+                "multiple images found for node {} in {}",
+                node_to_base(node, node_base),
+                dir.display()
+            );
+            images.push((node, path));
+        }
+    }
+
+    images.sort_by_key(|(node, _)| *node);
+    Ok(images)
+}
+
+/// Prints human readable status information. With `--json` it goes to
+/// stderr, so that stdout only contains parseable output.
+fn print_status(json: bool, msg: impl std::fmt::Display) {
+    if json {
+        eprintln!("{msg}");
+    } else {
+        println!("{msg}");
+    }
+}
+
+/// Resolves `--timeout` for the command. Uploads are never bounded, as they
+/// legitimately take minutes.
+fn request_timeout(args: &Cli) -> Option<Duration> {
+    let default = match args.command {
+        Some(Commands::Flash(_) | Commands::Firmware(_)) => return None,
+        Some(Commands::Healthcheck) => HEALTHCHECK_TIMEOUT,
+        _ => DEFAULT_TIMEOUT,
+    };
+    let timeout = args.timeout.map_or(default, Duration::from_secs);
+    (!timeout.is_zero()).then_some(timeout)
+}
+
+/// Prints the power state of every node, or of `only` that node.
+fn print_power_status_nodes(
+    map: &serde_json::Value,
+    node_base: u8,
+    only: Option<u8>,
+) -> anyhow::Result<()> {
+    let status: PowerStatus = parse_result_object(map)?;
+
+    // The BMC names nodes 1-based, e.g. `node1`. Sorting by that number keeps
+    // `node10` after `node9`, should a board ever have that many.
+    let mut nodes: Vec<_> = status
+        .nodes()
+        .map(|(key, on)| {
+            let node = key.strip_prefix("node").and_then(|n| n.parse::<u8>().ok());
+            (node, key, on)
+        })
+        .collect();
+    nodes.sort_by_key(|(node, _, _)| node.unwrap_or(u8::MAX));
+
+    for (node, key, on) in nodes {
+        if only.is_some_and(|only| node != Some(only)) {
+            continue;
+        }
+        let status = if on { "On" } else { "Off" };
+        match node {
+            Some(node) => println!("Node {}: {status}", node_to_base(node, node_base)),
+            None => println!("{key}: {status}"),
+        }
     }
 
     Ok(())
 }
 
 fn result_printer(result: &serde_json::Value) -> anyhow::Result<()> {
-    let res = get_json_str(result, "result");
-    println!("{}", res);
+    for line in format_result(result)? {
+        println!("{line}");
+    }
+
+    // Non-fatal remarks of the firmware go to stderr, keeping the result on
+    // stdout for scripting.
+    for key in ["warning", "message"] {
+        if let Some(note) = result.get(key) {
+            eprintln!("{key}: {}", display_value(note));
+        }
+    }
     Ok(())
 }
 
-fn info_printer(map: &serde_json::Value) -> anyhow::Result<()> {
-    let results = map
+/// Renders the `result` of a response as lines: a string as is, an object as
+/// `key: value` lines and an array as one line per element.
+fn format_result(result: &serde_json::Value) -> anyhow::Result<Vec<String>> {
+    let lines = match result
         .get("result")
-        .context("API error")?
-        .as_array()
-        .context("API error")?[0]
-        .as_object()
-        .context("response parse error")?;
+        .context("API error: expected `result` key")?
+    {
+        serde_json::Value::Object(map) => map
+            .iter()
+            .map(|(key, value)| format!("{key}: {}", display_value(value)))
+            .collect(),
+        serde_json::Value::Array(values) => values.iter().map(display_value).collect(),
+        value => vec![display_value(value)],
+    };
+    Ok(lines)
+}
+
+/// Renders strings without quotes, other values as JSON.
+fn display_value(value: &serde_json::Value) -> String {
+    value
+        .as_str()
+        .map_or_else(|| value.to_string(), str::to_owned)
+}
+
+fn info_printer(
+    map: &serde_json::Value,
+    version: ApiVersion,
+    scheme: Scheme,
+) -> anyhow::Result<()> {
+    let Info(results) = parse_result_object(map)?;
 
     println!("|{:-^10}|{:-^28}|", "key", "value");
     for (key, value) in results {
-        println!(" {:<10}: {}", key, value.as_str().expect("API error"));
+        println!(" {:<10}: {}", key, value);
     }
+    let api = version
+        .to_possible_value()
+        .expect("api version has a value");
+    println!(" {:<10}: {} ({scheme})", "api", api.get_name());
     println!("|{:-^10}|{:-^28}|", "", "");
     Ok(())
 }
 
+/// Returns the value of `field` of `tpi info`, which includes `api`.
+fn info_field(map: &serde_json::Value, version: ApiVersion, field: &str) -> anyhow::Result<String> {
+    let Info(mut results) = parse_result_object(map)?;
+    let api = version
+        .to_possible_value()
+        .expect("api version has a value");
+    results.insert("api".into(), api.get_name().to_string());
+
+    let Some(value) = results.remove(field) else {
+        let fields: Vec<_> = results.into_keys().collect();
+        bail!(TpiError::Usage(format!(
+            "unknown field '{field}'; available: [{}]",
+            fields.join(", ")
+        )));
+    };
+    Ok(value)
+}
+
 fn print_usb_status(map: &serde_json::Value) -> anyhow::Result<()> {
-    let results = &map
-        .get("result")
-        .context("API error")?
-        .as_array()
-        .context("API error")?[0];
+    let status: UsbStatus = parse_result_object(map)?;
 
-    let node = get_json_str(results, "node").to_lowercase();
-    let mode = get_json_str(results, "mode").to_lowercase();
-    let route = get_json_str(results, "route").to_lowercase();
+    let node = status.node.to_lowercase();
+    let mode = status.mode.to_lowercase();
+    let route = status.route.to_lowercase();
 
     println!("{:^12}-->{:^12}", "USB Host", "USB Device");
 
+    let route_label = usb_route_label(&route).to_string();
     let (host, device) = if mode == "host" {
         (node, route)
     } else {
@@ -714,77 +2778,254 @@ fn print_usb_status(map: &serde_json::Value) -> anyhow::Result<()> {
     };
 
     println!("{:^12}-->{:^12}", host, device);
+    println!("route: {route_label}");
 
     Ok(())
 }
 
+/// Names the side of the USB-bus that `--bmc` selects: the BMC chip or the
+/// USB-A port.
+fn usb_route_label(route: &str) -> &str {
+    match route {
+        "bmc" => "BMC",
+        "usb-a" | "usba" | "usb" => "USB-A",
+        other => other,
+    }
+}
+
 fn uart_printer(map: &serde_json::Value) -> anyhow::Result<()> {
-    let data = get_json_str(map, "uart");
+    let output: UartOutput = parse_response(map)?;
 
-    print!("{data}");
+    print!("{}", output.uart);
 
     Ok(())
 }
 
 fn cooling_printer(map: &serde_json::Value) -> anyhow::Result<()> {
-    if map.get("result").and_then(|r| r.as_str()).is_some() {
-        println!("{}", get_json_str(map, "result"));
-        return Ok(());
-    }
-
-    let results = map
-        .get("result")
-        .context("API error")?
-        .as_array()
-        .context("API error")?;
+    let devices = match parse_result(map)? {
+        CoolingStatus::Message(message) => {
+            println!("{message}");
+            return Ok(());
+        }
+        CoolingStatus::Devices(devices) => devices,
+    };
 
-    if results.is_empty() {
+    if devices.is_empty() {
         println!("No cooling devices found");
     } else {
         println!("|{:-^15}|{:-^7}|{:-^11}|", "Device", "Speed", "Max Speed");
-        for device in results {
-            let name = get_json_str(device, "device");
-            let speed = get_json_num(device, "speed");
-            let max_speed = get_json_num(device, "max_speed");
-            println!("|{:<15}|{:>7}|{:>11}|", name, speed, max_speed);
+        for device in devices {
+            println!(
+                "|{:<15}|{:>7}|{:>11}|",
+                device.device, device.speed, device.max_speed
+            );
         }
     }
 
     Ok(())
 }
 
-fn build_progress_bar(size: u64) -> ProgressBar {
-    let pb = ProgressBar::new(size);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.blue/blue}] {bytes}/{total_bytes} ({eta})",
-        )
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
-            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap();
-        })
-        .progress_chars("#>-"),
+/// Returns the initial delay and the update period of the progress watcher.
+fn progress_intervals(delay_ms: u64, interval_ms: u64) -> (Duration, Duration) {
+    (
+        Duration::from_millis(delay_ms),
+        Duration::from_millis(interval_ms),
+    )
+}
+
+fn cooling_device_names(map: &serde_json::Value) -> anyhow::Result<Vec<String>> {
+    let devices: Vec<CoolingDevice> = parse_result(map)?;
+    Ok(devices.into_iter().map(|device| device.device).collect())
+}
+
+/// Picks `device` out of the cooling devices the BMC lists.
+fn select_cooling_device(
+    mut devices: Vec<CoolingDevice>,
+    device: &str,
+) -> anyhow::Result<CoolingDevice> {
+    let Some(index) = devices.iter().position(|d| d.device == device) else {
+        let names: Vec<_> = devices.into_iter().map(|d| d.device).collect();
+        bail!(TpiError::Usage(format!(
+            "unknown cooling device '{device}'; available: [{}]",
+            names.join(", ")
+        )));
+    };
+    Ok(devices.swap_remove(index))
+}
+
+/// Fails with a usage error if `speed` exceeds the `max_speed` of `device`.
+fn ensure_cooling_speed(device: &CoolingDevice, speed: u32) -> anyhow::Result<()> {
+    ensure!(
+        u64::from(speed) <= device.max_speed,
+        TpiError::Usage(format!(
+            "speed {speed} is out of range for {}, which accepts 0 to {}; pass `--force` to \
+             set it anyway",
+            device.device, device.max_speed
+        ))
     );
-    pb
+    Ok(())
 }
 
-fn build_spinner() -> ProgressBar {
-    let pb = ProgressBar::new_spinner();
-    pb.enable_steady_tick(Duration::from_millis(120));
-    pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
-    pb
+/// Returns the temperature in °C among the `info` of the BMC, if any. Values
+/// in millidegrees, as Linux reports them, are converted.
+fn info_temperature(info: &Info) -> Option<f64> {
+    info.0
+        .iter()
+        .filter(|(key, _)| key.to_ascii_lowercase().contains("temp"))
+        .find_map(|(_, value)| {
+            let number: String = value
+                .trim()
+                .chars()
+                .take_while(|c| c.is_ascii_digit() || matches!(c, '.' | '-'))
+                .collect();
+            let temp = number.parse::<f64>().ok()?;
+            Some(if temp.abs() >= 1000.0 {
+                temp / 1000.0
+            } else {
+                temp
+            })
+        })
 }
 
-fn get_json_str<'m>(map: &'m serde_json::Value, key: &str) -> &'m str {
-    map.get(key)
-        .unwrap_or_else(|| panic!("API error: expected `{}` key", key))
-        .as_str()
-        .unwrap_or_else(|| panic!("API error: `{}` is not a string", key))
+/// Interpolates the speed of the fan curve `points`, sorted by temperature,
+/// at `temp`.
+fn curve_speed(points: &[CurvePoint], temp: f64) -> u32 {
+    let Some(upper) = points.iter().position(|p| p.temp >= temp) else {
+        return points.last().map_or(0, |p| p.speed);
+    };
+    if upper == 0 {
+        return points[0].speed;
+    }
+
+    // `lower.temp < temp <= upper.temp`, so the two differ.
+    let (lower, upper) = (points[upper - 1], points[upper]);
+    let t = (temp - lower.temp) / (upper.temp - lower.temp);
+    let speed = f64::from(lower.speed) + t * (f64::from(upper.speed) - f64::from(lower.speed));
+    speed.round() as u32
+}
+
+fn cooling_list_printer(map: &serde_json::Value) -> anyhow::Result<()> {
+    for name in cooling_device_names(map)? {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+fn cooling_list_json_printer(map: &serde_json::Value, pretty: bool) -> anyhow::Result<()> {
+    print_json(&serde_json::json!(cooling_device_names(map)?), pretty);
+    Ok(())
+}
+
+/// Prints `value` compactly on a single line, which suits piping, or
+/// indented when `pretty` is set.
+fn print_json(value: &serde_json::Value, pretty: bool) {
+    if pretty {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(value).expect("a Value always serializes")
+        );
+    } else {
+        println!("{}", value);
+    }
 }
 
-fn get_json_num(map: &serde_json::Value, key: &str) -> u64 {
-    map.get(key)
-        .unwrap_or_else(|| panic!("API error: expected `{}` key", key))
-        .as_u64()
-        .unwrap_or_else(|| panic!("API error: `{}` is not a number", key))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cooling_devices(names: &[&str]) -> Vec<CoolingDevice> {
+        names
+            .iter()
+            .map(|name| CoolingDevice {
+                device: name.to_string(),
+                speed: 0,
+                max_speed: 4,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn select_cooling_device_finds_listed_device() {
+        let devices = cooling_devices(&["fan0", "fan1"]);
+        let found = select_cooling_device(devices, "fan1").unwrap();
+        assert_eq!(found.device, "fan1");
+        assert_eq!(found.max_speed, 4);
+    }
+
+    #[test]
+    fn select_cooling_device_lists_available_devices() {
+        let devices = cooling_devices(&["fan0", "fan1"]);
+        let err = select_cooling_device(devices, "fan2").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TpiError>(),
+            Some(TpiError::Usage(_))
+        ));
+        assert_eq!(
+            err.to_string(),
+            "unknown cooling device 'fan2'; available: [fan0, fan1]"
+        );
+    }
+
+    #[test]
+    fn string_result_is_printed_as_is() {
+        let response = serde_json::json!({ "result": "ok" });
+        assert_eq!(format_result(&response).unwrap(), ["ok"]);
+    }
+
+    #[test]
+    fn object_result_is_printed_as_key_value_lines() {
+        let response = serde_json::json!({ "result": { "node": "1", "speed": 3 } });
+        assert_eq!(format_result(&response).unwrap(), ["node: 1", "speed: 3"]);
+    }
+
+    #[test]
+    fn array_result_is_printed_one_element_per_line() {
+        let response = serde_json::json!({ "result": ["fan0", { "speed": 3 }] });
+        assert_eq!(
+            format_result(&response).unwrap(),
+            ["fan0", r#"{"speed":3}"#]
+        );
+    }
+
+    /// Creates an empty directory of its own for `test`.
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tpi-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn readable_file_rejects_a_directory() {
+        let dir = temp_dir("readable-dir");
+        let err = ensure_readable_file(&dir).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TpiError>(),
+            Some(TpiError::Usage(_))
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!("{} is a directory, expected a file", dir.display())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn readable_file_rejects_a_missing_file() {
+        let dir = temp_dir("readable-missing");
+        let path = dir.join("missing.img");
+        let err = ensure_readable_file(&path).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TpiError>(),
+            Some(TpiError::Usage(_))
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!("image not found: {}", path.display())
+        );
+
+        std::fs::write(&path, b"image").unwrap();
+        ensure_readable_file(&path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
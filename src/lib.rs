@@ -0,0 +1,29 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Library side of tpi. [`client::BmcClient`] drives a Turing Pi BMC without
+//! going through the command line interface.
+
+// The command line and the interactive prompt belong to the tpi binary, they
+// are only public so that it can use them.
+#[doc(hidden)]
+pub mod cli;
+pub mod client;
+pub mod error;
+pub mod profile;
+pub mod progress;
+#[doc(hidden)]
+pub mod prompt;
+pub mod request;
+pub mod response;
@@ -14,57 +14,238 @@
 
 #[cfg(feature = "localhost")]
 mod board_info;
-mod cli;
 mod legacy_handler;
-mod prompt;
-mod request;
+mod netrc;
+mod utils;
 
+use tpi::{cli, client, error, profile, progress, prompt, request, response};
+
+use crate::error::TpiError;
 use crate::legacy_handler::LegacyHandler;
-use clap::{CommandFactory, Parser};
-use clap_complete::generate;
-use cli::Cli;
-use std::{io, process::ExitCode};
+use crate::profile::Profiler;
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use clap_complete::{generate, generate_to};
+use cli::{ApiVersion, Cli};
+use std::{ffi::OsString, io, net::Ipv6Addr, process::ExitCode};
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    let cli = Cli::parse();
+    // Everything after a `--` is not parsed by clap, but sent as extra query
+    // parameters with the request of the command.
+    let mut args = expand_arg_files(std::env::args_os());
+    let passthrough = match args.iter().position(|arg| arg == "--") {
+        Some(idx) => args.split_off(idx).split_off(1),
+        None => Vec::new(),
+    };
+
+    let mut cli = Cli::parse_from(args);
+    for param in passthrough {
+        match cli::parse_key_value(&param.to_string_lossy()) {
+            Ok(pair) => cli.query_params.push(pair),
+            Err(e) => Cli::command().error(ErrorKind::InvalidValue, e).exit(),
+        }
+    }
+    if let Err(e) = cli.normalize_nodes() {
+        Cli::command().error(ErrorKind::ValueValidation, e).exit();
+    }
     if let Some(shell) = cli.gencompletion {
-        generate(
-            shell,
-            &mut Cli::command(),
-            env!("CARGO_PKG_NAME"),
-            &mut io::stdout(),
-        );
-        return ExitCode::SUCCESS;
+        if cli.command.is_some() {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "`-g` cannot be used with a subcommand",
+                )
+                .exit();
+        }
+        let mut command = Cli::command();
+        let Some(dir) = &cli.out_dir else {
+            generate(
+                shell,
+                &mut command,
+                env!("CARGO_PKG_NAME"),
+                &mut io::stdout(),
+            );
+            return ExitCode::SUCCESS;
+        };
+
+        return match generate_to(shell, &mut command, env!("CARGO_PKG_NAME"), dir) {
+            Ok(path) => {
+                println!("{}", path.display());
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("cannot write completions to {}: {e}", dir.display());
+                ExitCode::from(error::EXIT_FAILURE)
+            }
+        };
     }
 
-    if let Err(e) = execute_cli_command(&cli).await {
-        if let Some(error) = e.downcast_ref::<reqwest::Error>() {
-            println!("{error}");
-        } else {
-            println!("{:#}", e);
+    match execute_cli_command(&cli).await {
+        Ok(code) => code,
+        Err(e) => {
+            if let Some(error) = e.downcast_ref::<reqwest::Error>() {
+                println!("{error}");
+            } else {
+                println!("{:#}", e);
+            }
+            ExitCode::from(error::exit_code(&e))
         }
-        return ExitCode::FAILURE;
     }
+}
 
-    ExitCode::SUCCESS
+/// Replaces every `@file` argument by the whitespace separated arguments
+/// read from that file, e.g. `tpi @flash.args`.
+fn expand_arg_files(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        let path = match arg.to_str().and_then(|a| a.strip_prefix('@')) {
+            Some(path) if !path.is_empty() => path,
+            _ => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+
+        match std::fs::read_to_string(path) {
+            Ok(contents) => expanded.extend(contents.split_whitespace().map(OsString::from)),
+            Err(e) => Cli::command()
+                .error(
+                    ErrorKind::Io,
+                    format!("cannot read arguments from {path}: {e}"),
+                )
+                .exit(),
+        }
+    }
+    expanded
 }
 
-async fn execute_cli_command(cli: &Cli) -> anyhow::Result<()> {
+async fn execute_cli_command(cli: &Cli) -> anyhow::Result<ExitCode> {
     let command = cli.command.as_ref().ok_or_else(|| {
-        anyhow::anyhow!(
+        TpiError::Usage(format!(
             "subcommand must be specified!\n\n{}",
             Cli::command().render_long_help()
-        )
+        ))
     })?;
 
-    let host = url::Host::parse(cli.host.as_ref().expect("host has a default set"))
-        .map_err(|_| anyhow::anyhow!("please enter a valid hostname"))?;
-    let mut host = host.to_string();
+    let input = cli.host.as_ref().expect("host has a default set");
+    let mut host = parse_host(input)?;
     // connect to specific port if specified.
     if let Some(port) = cli.port {
         host.push_str(&format!(":{}", port));
     }
 
-    LegacyHandler::new(host, cli)?.handle_cmd(command).await
+    let profiler = cli.profile.then(Profiler::default);
+    let result = LegacyHandler::new(host.clone(), cli, profiler.clone())?
+        .handle_cmd(command)
+        .await;
+
+    if let Err(e) = &result {
+        let version = cli.api_version.expect("Missing API version");
+        diagnose_api_version(&host, version, e).await;
+    }
+
+    if let Some(profiler) = profiler {
+        profiler.print();
+    }
+    result
+}
+
+/// Transport errors are what a BMC speaking the other API version typically
+/// produces, e.g. a failing TLS handshake. In that case, check if the other
+/// version gets an answer and point the user to it.
+async fn diagnose_api_version(host: &str, version: ApiVersion, error: &anyhow::Error) {
+    let transport_error = error.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| !e.is_status())
+    });
+    if !transport_error {
+        return;
+    }
+
+    let (other, speaks) = match version {
+        ApiVersion::V1 => (ApiVersion::V1_1, "v1.1"),
+        ApiVersion::V1_1 => (ApiVersion::V1, "v1"),
+    };
+
+    if request::responds_to(host, other).await {
+        let arg = other.to_possible_value().expect("no skipped variants");
+        eprintln!(
+            "API version mismatch: the BMC appears to speak {speaks}, retry with `-a {}`",
+            arg.get_name()
+        );
+    }
+}
+
+/// Validates the `--host` argument and returns it the way it goes into a URL.
+fn parse_host(input: &str) -> anyhow::Result<String> {
+    let input = &bracket_ipv6(input);
+    let host = url::Host::parse(input).map_err(|e| invalid_host_error(input, e))?;
+    Ok(host.to_string())
+}
+
+/// URLs require IPv6 addresses to be wrapped in square brackets, add them
+/// when a bare address was passed.
+fn bracket_ipv6(input: &str) -> String {
+    if input.parse::<Ipv6Addr>().is_ok() {
+        format!("[{input}]")
+    } else {
+        input.to_string()
+    }
+}
+
+fn invalid_host_error(input: &str, error: url::ParseError) -> anyhow::Error {
+    if input.starts_with('[') || input.matches(':').count() > 1 {
+        TpiError::Usage(format!("`{input}` is not a valid IPv6 address: {error}")).into()
+    } else if input.contains(':') {
+        TpiError::Usage(format!(
+            "`{input}` is not a valid hostname: {error}\n\
+            Note: use `--port` to connect to a custom port"
+        ))
+        .into()
+    } else {
+        TpiError::Usage(format!("`{input}` is not a valid hostname: {error}")).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage_error(input: &str) -> String {
+        let err = parse_host(input).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TpiError>(),
+            Some(TpiError::Usage(_))
+        ));
+        err.to_string()
+    }
+
+    #[test]
+    fn bracketed_ipv6_is_kept() {
+        assert_eq!(parse_host("[fe80::1]").unwrap(), "[fe80::1]");
+    }
+
+    #[test]
+    fn bare_ipv6_is_bracketed() {
+        assert_eq!(bracket_ipv6("fe80::1"), "[fe80::1]");
+        assert_eq!(parse_host("fe80::1").unwrap(), "[fe80::1]");
+        assert_eq!(parse_host("turingpi.local").unwrap(), "turingpi.local");
+    }
+
+    #[test]
+    fn invalid_hosts_are_usage_errors() {
+        assert_eq!(
+            usage_error("[fe80::zz]"),
+            "`[fe80::zz]` is not a valid IPv6 address: invalid IPv6 address"
+        );
+        assert_eq!(
+            usage_error("fe80::1::2"),
+            "`fe80::1::2` is not a valid IPv6 address: invalid international domain name"
+        );
+        assert_eq!(
+            usage_error("turingpi.local:8080"),
+            "`turingpi.local:8080` is not a valid hostname: invalid international domain name\n\
+            Note: use `--port` to connect to a custom port"
+        );
+    }
 }
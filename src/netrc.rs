@@ -0,0 +1,63 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal reader for credentials stored in a netrc file, as used by curl and
+//! ftp. Only `machine`, `default`, `login` and `password` are interpreted.
+
+use std::path::PathBuf;
+
+/// Returns the login and password of the netrc entry for `host`, falling back
+/// on the `default` entry. The file is read from `$NETRC`, or `~/.netrc`.
+pub fn credentials(host: &str) -> Option<(String, String)> {
+    let path = std::env::var_os("NETRC")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".netrc")))?;
+    let text = std::fs::read_to_string(path).ok()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    parse(&text, host)
+}
+
+fn parse(text: &str, host: &str) -> Option<(String, String)> {
+    let mut tokens = text.split_whitespace();
+    let mut matches = false;
+    let mut login = None;
+    let mut password = None;
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" => {
+                if matches {
+                    break;
+                }
+                matches = token == "default"
+                    || tokens
+                        .next()
+                        .is_some_and(|m| m.trim_start_matches('[').trim_end_matches(']') == host);
+                login = None;
+                password = None;
+            }
+            "login" => login = tokens.next(),
+            "password" => password = tokens.next(),
+            "account" | "macdef" => {
+                tokens.next();
+            }
+            _ => {}
+        }
+    }
+
+    match (matches, login, password) {
+        (true, Some(login), Some(password)) => Some((login.to_string(), password.to_string())),
+        _ => None,
+    }
+}
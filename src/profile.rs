@@ -0,0 +1,82 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Collects the time spent in the phases of a command for `--profile`.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+#[derive(Clone, Default)]
+pub struct Profiler {
+    phases: Arc<Mutex<Vec<Phase>>>,
+}
+
+struct Phase {
+    name: &'static str,
+    count: u32,
+    total: Duration,
+}
+
+impl Profiler {
+    /// Adds `elapsed` to the given phase. Phases are reported in the order
+    /// they were first recorded.
+    pub fn record(&self, name: &'static str, elapsed: Duration) {
+        let mut phases = self.phases.lock().expect("profiler lock poisoned");
+        match phases.iter_mut().find(|p| p.name == name) {
+            Some(phase) => {
+                phase.count += 1;
+                phase.total += elapsed;
+            }
+            None => phases.push(Phase {
+                name,
+                count: 1,
+                total: elapsed,
+            }),
+        }
+    }
+
+    /// Prints the collected timings to stderr, keeping stdout clean for the
+    /// output of the command.
+    pub fn print(&self) {
+        let phases = self.phases.lock().expect("profiler lock poisoned");
+        eprintln!("|{:-^10}|{:-^7}|{:-^12}|", "phase", "count", "total");
+        for phase in phases.iter() {
+            eprintln!(
+                " {:<10} {:>7} {:>11.3}s",
+                phase.name,
+                phase.count,
+                phase.total.as_secs_f64()
+            );
+        }
+        eprintln!("|{:-^10}|{:-^7}|{:-^12}|", "", "", "");
+    }
+}
+
+/// Resolves host names with the system resolver, like reqwest does by
+/// default, recording the time spent as the `dns` phase.
+pub struct ProfilingResolver(pub Profiler);
+
+impl Resolve for ProfilingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let profiler = self.0.clone();
+        Box::pin(async move {
+            let start = Instant::now();
+            let addrs: Vec<_> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            profiler.record("dns", start.elapsed());
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
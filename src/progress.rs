@@ -0,0 +1,190 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Progress of flashing a node, as events that a front end renders however it
+//! sees fit.
+
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use reqwest::Client;
+
+use crate::error::TpiError;
+use crate::request::Request;
+use crate::response::{parse_response, FlashProgress};
+
+/// A change in the progress of a flash, see [`FlashWatcher::poll`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlashEvent {
+    /// The BMC started writing an image of `size` bytes.
+    Started { size: u64 },
+    /// The BMC wrote `bytes_written` bytes so far.
+    Transferring { bytes_written: u64 },
+    /// The image is written, the BMC is verifying it.
+    Verifying,
+    /// The flash completed. `recorded` is the number of bytes the BMC wrote
+    /// and `checksum` the digest it computed of them, if it reported them.
+    /// `recorded` only comes from the final state of the BMC, never from a
+    /// count sampled while the image was still being written.
+    Done {
+        recorded: Option<u64>,
+        checksum: Option<String>,
+    },
+}
+
+/// Tracks the flash identified by a transfer handle.
+pub struct FlashWatcher {
+    request: Request,
+    handle: u64,
+    started: bool,
+    verifying: bool,
+    completed: Option<u64>,
+}
+
+impl FlashWatcher {
+    /// `request` is any request to the BMC, it only provides the address and
+    /// credentials.
+    pub fn new(request: &Request, handle: u64) -> Self {
+        Self {
+            request: progress_request(request),
+            handle,
+            started: false,
+            verifying: false,
+            completed: None,
+        }
+    }
+
+    /// Queries the progress once. Returns what changed since the last poll,
+    /// `None` if nothing did. Poll again until [`FlashEvent::Done`]. A flash
+    /// that failed on the BMC is an error, an integrity error when it failed
+    /// verification.
+    pub async fn poll(&mut self, client: &Client) -> Result<Option<FlashEvent>> {
+        let event = match flash_progress(client, &self.request).await? {
+            FlashProgress::Transferring {
+                id,
+                size,
+                bytes_written,
+            } => {
+                ensure!(
+                    id == self.handle,
+                    TpiError::Bmc(format!(
+                        "BMC reports progress of transfer {id}, expected {}",
+                        self.handle
+                    ))
+                );
+                // A count below `size` may be a sample of a transfer that
+                // completed right after, only a full one is final.
+                if bytes_written >= size {
+                    self.completed = Some(size);
+                }
+
+                if !self.started {
+                    self.started = true;
+                    Some(FlashEvent::Started { size })
+                } else if bytes_written < size {
+                    Some(FlashEvent::Transferring { bytes_written })
+                } else if !self.verifying {
+                    self.verifying = true;
+                    Some(FlashEvent::Verifying)
+                } else {
+                    None
+                }
+            }
+            FlashProgress::Done(payload) => Some(FlashEvent::Done {
+                recorded: reported_length(&payload).or(self.completed),
+                checksum: reported_checksum(&payload),
+            }),
+            FlashProgress::Error(map) => {
+                let msg = format!("Error occured during flashing: {}", map);
+                if self.verifying {
+                    bail!(TpiError::Integrity(msg));
+                }
+                bail!(TpiError::Bmc(msg));
+            }
+        };
+        Ok(event)
+    }
+}
+
+/// Queries the state of the flash the BMC is busy with, if any. `request` is
+/// any request to the BMC, it only provides the address and credentials.
+pub async fn current_flash(client: &Client, request: &Request) -> Result<FlashProgress> {
+    flash_progress(client, &progress_request(request)).await
+}
+
+/// Returns the checksum in the payload of `Done`. Only newer firmware reports
+/// it, under one of a few names.
+/// Spreads `interval` randomly over `interval * (1 ± fraction)`, so that
+/// several clients polling the same BMC do not do so in lockstep.
+pub fn jittered(interval: Duration, fraction: f64) -> Duration {
+    if fraction <= 0.0 {
+        return interval;
+    }
+
+    let random: f64 = rand::random();
+    interval.mul_f64(1.0 + fraction * (2.0 * random - 1.0))
+}
+
+fn reported_checksum(payload: &serde_json::Value) -> Option<String> {
+    ["sha256", "checksum", "crc"]
+        .iter()
+        .find_map(|key| match payload.get(key)? {
+            serde_json::Value::String(checksum) => Some(checksum.clone()),
+            serde_json::Value::Number(checksum) => Some(checksum.to_string()),
+            _ => None,
+        })
+}
+
+/// Returns the number of bytes written in the payload of `Done`, if the
+/// firmware reports it.
+fn reported_length(payload: &serde_json::Value) -> Option<u64> {
+    ["bytes_written", "size"]
+        .iter()
+        .find_map(|key| payload.get(key)?.as_u64())
+}
+
+fn progress_request(request: &Request) -> Request {
+    let mut request = request.clone();
+    request
+        .url_mut()
+        .query_pairs_mut()
+        .clear()
+        .append_pair("opt", "get")
+        .append_pair("type", "flash");
+    request
+}
+
+async fn flash_progress(client: &Client, request: &Request) -> Result<FlashProgress> {
+    let response = request
+        .clone()
+        .send(client.clone())
+        .await
+        .context("failed to request the flashing progress")?;
+
+    let status = response.status();
+    let json = response
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| TpiError::Bmc(format!("invalid flashing progress: {e}")))?;
+
+    if !status.is_success() {
+        let reason = json.get("response").cloned().unwrap_or_default();
+        bail!(TpiError::Bmc(format!(
+            "Failed to get flashing progress: {status}: {reason}"
+        )));
+    }
+
+    parse_response(&json)
+        .map_err(|_| TpiError::Bmc(format!("Unexpected response: {:#?}", json)).into())
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::{stdout, Write};
+use std::io::{stdin, stdout, Write};
 
 use anyhow::{bail, Result};
 use crossterm::cursor::MoveToColumn;
@@ -39,7 +39,11 @@ impl Prompt {
     }
 
     fn read(&mut self) -> Result<String> {
-        enable_raw_mode()?;
+        // Without a terminal that supports raw mode, e.g. when stdin is a
+        // pipe, fall back on reading a plain line.
+        if enable_raw_mode().is_err() {
+            return self.read_line();
+        }
 
         let res = self.read_loop();
 
@@ -51,6 +55,17 @@ impl Prompt {
         }
     }
 
+    fn read_line(&self) -> Result<String> {
+        print!("{}: ", self.msg);
+        stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin().read_line(&mut line)? == 0 {
+            bail!("no input for `{}`: end of input reached", self.msg);
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
     fn read_loop(&mut self) -> Result<()> {
         loop {
             self.print()?;
@@ -101,23 +116,13 @@ impl Prompt {
                 self.input.insert(self.cursor_idx, c);
                 self.cursor_idx += 1;
             }
-            KeyCode::Delete => {
-                if !self.input.is_empty() {
-                    self.delete();
-                }
-            }
-            KeyCode::Backspace => {
-                if !self.input.is_empty() {
-                    self.left();
-                    self.delete();
-                }
+            KeyCode::Delete if !self.input.is_empty() => self.delete(),
+            KeyCode::Backspace if !self.input.is_empty() => {
+                self.left();
+                self.delete();
             }
             KeyCode::Left => self.left(),
-            KeyCode::Right => {
-                if self.cursor_idx < self.input.len() - 1 {
-                    self.cursor_idx += 1;
-                }
-            }
+            KeyCode::Right if self.cursor_idx + 1 < self.input.len() => self.cursor_idx += 1,
             _ => {}
         }
 
@@ -144,3 +149,9 @@ pub fn simple(msg: &'static str) -> Result<String> {
 pub fn password(msg: &'static str) -> Result<String> {
     Prompt::new(msg, true).read()
 }
+
+/// Asks a yes/no question. Anything but an explicit yes counts as no.
+pub fn confirm(msg: &'static str) -> Result<bool> {
+    let answer = Prompt::new(msg, false).read()?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
@@ -16,23 +16,82 @@
 
 use std::io::Write;
 use std::ops::{Deref, DerefMut};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Result};
-use reqwest::header::{HeaderValue, USER_AGENT};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use reqwest::header::{HeaderValue, RETRY_AFTER, USER_AGENT};
 use reqwest::multipart::Form;
-use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use reqwest::redirect::Policy;
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::time::sleep;
 use url::Url;
 
-use crate::cli::ApiVersion;
+use crate::error::TpiError;
+use crate::profile::Profiler;
 use crate::prompt;
 
+/// Upper bound for probing which API version the BMC speaks.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Wait before the first retry of a failed request, doubled for each further one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+/// Upper bound of the wait between two attempts, including one the BMC asks
+/// for with `Retry-After`.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Version of the HTTP API of the BMC.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+    V1_1,
+}
+
+impl ApiVersion {
+    /// The scheme the BMC serves this API version on, unless `--scheme` says otherwise.
+    pub fn scheme(&self) -> Scheme {
+        match self {
+            ApiVersion::V1 => Scheme::Http,
+            ApiVersion::V1_1 => Scheme::Https,
+        }
+    }
+}
+
+/// Scheme the BMC is reached over.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+impl std::fmt::Display for Scheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 pub struct Request {
     host: String,
-    ver: ApiVersion,
+    version: ApiVersion,
+    scheme: Scheme,
     creds: (Option<String>, Option<String>),
+    batch: bool,
     inner: reqwest::Request,
     multipart: Option<Form>,
+    body: Option<Body>,
+    profiler: Option<Profiler>,
+    retries: u32,
+    token_cache: Option<PathBuf>,
 }
 
 impl Request {
@@ -40,33 +99,47 @@ impl Request {
         host: String,
         ver: ApiVersion,
         creds: (Option<String>, Option<String>),
+        batch: bool,
         user_agent: &str,
     ) -> Result<Self> {
-        let url = url_from_host(&host, ver.scheme())?;
+        let scheme = ver.scheme();
+        let url = url_from_host(&host, scheme)?;
         let mut inner = reqwest::Request::new(Method::GET, url);
         inner
             .headers_mut()
             .insert(USER_AGENT, HeaderValue::from_str(user_agent)?);
 
         Ok(Self {
-            host,
-            ver,
+            version: ver,
+            scheme,
             creds,
+            batch,
             inner,
             multipart: None,
+            body: None,
+            profiler: None,
+            retries: 0,
+            token_cache: default_token_cache(&host),
+            host,
         })
     }
 
     pub fn to_post(&self) -> Result<Self> {
-        let url = url_from_host(&self.host, self.ver.scheme())?;
+        let url = url_from_host(&self.host, self.scheme)?;
         let inner = reqwest::Request::new(Method::POST, url);
 
         Ok(Self {
             host: self.host.clone(),
-            ver: self.ver,
+            version: self.version,
+            scheme: self.scheme,
             creds: self.creds.clone(),
+            batch: self.batch,
             inner,
             multipart: None,
+            body: None,
+            profiler: self.profiler.clone(),
+            retries: self.retries,
+            token_cache: self.token_cache.clone(),
         })
     }
 
@@ -74,25 +147,123 @@ impl Request {
         self.multipart = Some(form);
     }
 
+    /// Sets a streaming body. Unlike the headers, the body is not kept when the
+    /// request is cloned.
+    pub fn set_body(&mut self, body: Body) {
+        self.body = Some(body);
+    }
+
+    /// Overrides the scheme that follows from the API version.
+    pub fn set_scheme(&mut self, scheme: Scheme) {
+        self.scheme = scheme;
+        self.inner
+            .url_mut()
+            .set_scheme(scheme.as_str())
+            .expect("http and https are interchangeable");
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    pub fn version(&self) -> ApiVersion {
+        self.version
+    }
+
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    /// Retries a query up to `retries` times when the BMC is unreachable or
+    /// answers 502 or 503, as its web server does while it boots. Requests
+    /// that change anything are never retried.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// Caches the token in `path` instead of the default location, which is
+    /// specific to the host.
+    pub fn set_token_cache(&mut self, path: PathBuf) {
+        self.token_cache = Some(path);
+    }
+
+    fn is_query(&self) -> bool {
+        self.inner.method() == Method::GET
+            && self
+                .url()
+                .query_pairs()
+                .any(|(key, value)| key == "opt" && value == "get")
+    }
+
+    fn record(&self, phase: &'static str, start: Instant) {
+        if let Some(profiler) = &self.profiler {
+            profiler.record(phase, start.elapsed());
+        }
+    }
+
     pub async fn send(mut self, client: Client) -> Result<Response> {
         let mut authenticated = cfg!(not(feature = "localhost"));
+        let retries = if self.is_query() { self.retries } else { 0 };
+        let mut attempt = 0;
 
         let resp = loop {
             let mut builder =
                 RequestBuilder::from_parts(client.clone(), self.inner.try_clone().unwrap());
 
             if authenticated {
-                let token = self.get_bearer_token(&client).await?;
+                let start = Instant::now();
+                let token = match self.get_bearer_token(&client).await {
+                    Err(e) if attempt < retries && is_connect_error(&e) => {
+                        sleep(retry_backoff(attempt)).await;
+                        attempt += 1;
+                        continue;
+                    }
+                    token => token?,
+                };
+                self.record("auth", start);
                 builder = builder.bearer_auth(token);
             }
 
-            if let Some(form) = self.multipart.take() {
+            let phase = if let Some(form) = self.multipart.take() {
                 builder = builder.multipart(form);
+                "upload"
+            } else if let Some(body) = self.body.take() {
+                builder = builder.body(body);
+                "upload"
+            } else {
+                "request"
+            };
+
+            let start = Instant::now();
+            let result = builder.send().await;
+            self.record(phase, start);
+
+            let transient = match &result {
+                Ok(resp) => matches!(
+                    resp.status(),
+                    StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE
+                ),
+                Err(e) => e.is_connect(),
+            };
+            if transient && attempt < retries {
+                let wait = match result.as_ref().ok().and_then(retry_after) {
+                    Some(secs) => Duration::from_secs(secs).min(MAX_RETRY_BACKOFF),
+                    None => retry_backoff(attempt),
+                };
+                sleep(wait).await;
+                attempt += 1;
+                continue;
             }
 
-            let resp = builder.send().await?;
+            let resp = result?;
             if resp.status() == StatusCode::UNAUTHORIZED {
-                delete_cached_token();
+                if self.batch && authenticated {
+                    bail!(TpiError::Auth("authentication rejected by the BMC".into()));
+                }
+                // Batch mode never touches the cached token.
+                if !self.batch {
+                    delete_cached_token(self.token_cache.as_deref());
+                }
                 authenticated = true;
             } else {
                 break resp;
@@ -105,16 +276,37 @@ impl Request {
     async fn get_bearer_token(&mut self, client: &Client) -> Result<String> {
         // If either credentials are supplied, use them
         if self.creds.0.is_some() || self.creds.1.is_some() {
-            return request_token(&self.host, self.ver, &self.creds, client).await;
+            return self.request_token(client).await;
+        }
+
+        if self.batch {
+            bail!(batch_credentials_error());
         }
 
         // Else, try retrieving cached token from a file
-        if let Some(token) = get_cached_token() {
+        if let Some(token) = get_cached_token(self.token_cache.as_deref()) {
             return Ok(token);
         }
 
         // If it doesn't exist, ask on an interactive prompt
-        request_token(&self.host, self.ver, &self.creds, client).await
+        self.request_token(client).await
+    }
+
+    /// Requests a new token from the BMC, ignoring any cached token.
+    pub async fn login(&self, client: &Client) -> Result<String> {
+        self.request_token(client).await
+    }
+
+    async fn request_token(&self, client: &Client) -> Result<String> {
+        request_token(
+            &self.host,
+            self.scheme,
+            &self.creds,
+            self.batch,
+            self.token_cache.as_deref(),
+            client,
+        )
+        .await
     }
 
     pub fn url(&self) -> &Url {
@@ -124,8 +316,11 @@ impl Request {
     pub fn url_mut(&mut self) -> &mut Url {
         self.inner.url_mut()
     }
+}
 
-    pub fn clone(&self) -> Self {
+/// The body is not cloned, it has to be attached again.
+impl Clone for Request {
+    fn clone(&self) -> Self {
         let inner = self
             .inner
             .try_clone()
@@ -133,10 +328,16 @@ impl Request {
 
         Self {
             host: self.host.clone(),
-            ver: self.ver,
+            version: self.version,
+            scheme: self.scheme,
             creds: self.creds.clone(),
+            batch: self.batch,
             inner,
             multipart: None,
+            body: None,
+            profiler: self.profiler.clone(),
+            retries: self.retries,
+            token_cache: self.token_cache.clone(),
         }
     }
 }
@@ -155,39 +356,92 @@ impl DerefMut for Request {
     }
 }
 
-fn url_from_host(host: &str, scheme: &str) -> Result<Url> {
-    let mut url = Url::parse(&format!("{}://{}", scheme, host))?;
-    url.set_path("api/bmc");
-    Ok(url)
+/// Tells whether a BMC answers on `host` using the given API version. This is
+/// used to diagnose failures caused by a mismatching `-a` argument, hence
+/// redirects (e.g. from http to https) do not count as an answer.
+pub async fn responds_to(host: &str, ver: ApiVersion) -> bool {
+    let Ok(url) = url_from_host(host, ver.scheme()) else {
+        return false;
+    };
+
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .redirect(Policy::none())
+        .timeout(PROBE_TIMEOUT)
+        .build();
+
+    match client {
+        Ok(client) => client
+            .get(url)
+            .send()
+            .await
+            .is_ok_and(|resp| !resp.status().is_redirection()),
+        Err(_) => false,
+    }
+}
+
+fn retry_backoff(attempt: u32) -> Duration {
+    RETRY_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_BACKOFF)
 }
 
-fn get_cached_token() -> Option<String> {
-    let path = get_cache_file_location();
-    let file = std::fs::read_to_string(path);
+/// Returns the seconds a busy BMC asks to wait before retrying, from the
+/// `Retry-After` header of `response`.
+pub fn retry_after(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+}
 
-    file.ok()
+fn is_connect_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_connect)
+    })
 }
 
-fn delete_cached_token() {
-    let _ = std::fs::remove_file(get_cache_file_location());
+fn url_from_host(host: &str, scheme: Scheme) -> Result<Url> {
+    let mut url = Url::parse(&format!("{}://{}", scheme, host))?;
+    url.set_path("api/bmc");
+    Ok(url)
 }
 
-fn get_cache_file_location() -> PathBuf {
-    let default = PathBuf::from(".");
-    let mut path = dirs::cache_dir().unwrap_or(default);
+fn get_cached_token(path: Option<&Path>) -> Option<String> {
+    std::fs::read_to_string(path?)
+        .ok()
+        .filter(|token| !token.is_empty())
+}
 
-    path.push("tpi_token");
+fn delete_cached_token(path: Option<&Path>) {
+    if let Some(path) = path {
+        let _ = std::fs::remove_file(path);
+    }
+}
 
-    path
+/// Default location of the cached token of `host`, or `None` when the platform
+/// has no cache directory. In that case tokens are not cached at all, rather
+/// than being written to the current working directory. The file name contains
+/// a hash of the host, so that tokens of different BMCs do not overwrite each
+/// other.
+fn default_token_cache(host: &str) -> Option<PathBuf> {
+    let digest = Sha256::digest(host.as_bytes());
+    let mut path = dirs::cache_dir()?;
+    path.push(format!("tpi_token_{}", hex::encode(&digest[..8])));
+    Some(path)
 }
 
 async fn request_token(
     host: &str,
-    ver: ApiVersion,
+    scheme: Scheme,
     creds: &(Option<String>, Option<String>),
+    batch: bool,
+    token_cache: Option<&Path>,
     client: &Client,
 ) -> Result<String> {
-    let mut auth_url = url_from_host(host, ver.scheme())?;
+    let mut auth_url = url_from_host(host, scheme)?;
 
     auth_url
         .path_segments_mut()
@@ -195,10 +449,13 @@ async fn request_token(
         .push("authenticate");
 
     // Save token to a file only if credentials weren't supplied from the command line
-    let save_token = creds.0.is_none() && creds.1.is_none();
+    let save_token = !batch && creds.0.is_none() && creds.1.is_none();
 
     let (username, password) = match creds.clone() {
         (Some(username), Some(password)) => (username, password),
+        _ if batch => {
+            bail!(batch_credentials_error())
+        }
         (Some(username), None) => {
             let password = prompt::password("Password")?;
             (username, password)
@@ -227,24 +484,30 @@ async fn request_token(
             let token = get_param(&json, "id");
 
             if save_token {
-                if let Err(e) = cache_token(&token) {
-                    let path = get_cache_file_location();
-                    println!("Warning: failed to write to cache file {:?}: {}", path, e);
+                if let Err(e) = cache_token(token_cache, &token) {
+                    eprintln!(
+                        "Warning: token is not cached, you will be asked to log in again: {e:#}"
+                    );
                 }
             }
 
             Ok(token)
         }
-        StatusCode::FORBIDDEN => bail!(
-            "{}",
+        StatusCode::FORBIDDEN => bail!(TpiError::Auth(
             resp.text()
                 .await
                 .unwrap_or("could not authenticate".to_string())
-        ),
+        )),
         x => bail!("Unexpected status code {x}"),
     }
 }
 
+fn batch_credentials_error() -> TpiError {
+    TpiError::Auth(
+        "credentials are required in batch mode, supply `--user` and `--password`".into(),
+    )
+}
+
 fn get_param(results: &serde_json::Value, key: &str) -> String {
     results
         .get(key)
@@ -254,15 +517,22 @@ fn get_param(results: &serde_json::Value, key: &str) -> String {
         .to_owned()
 }
 
-fn cache_token(token: &str) -> Result<()> {
-    let path = get_cache_file_location();
+fn cache_token(path: Option<&Path>, token: &str) -> Result<()> {
+    let Some(path) = path else {
+        bail!("no cache directory available");
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    }
 
     std::fs::OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
-        .open(path)?
-        .write_all(token.as_bytes())?;
+        .open(path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+        .with_context(|| format!("cannot write {}", path.display()))?;
 
     Ok(())
 }
@@ -0,0 +1,156 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed representations of the responses of the BMC. A response that does
+//! not match its type fails to deserialize, instead of surfacing as a missing
+//! key somewhere down the line.
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::collections::BTreeMap;
+
+/// Deserializes the `result` of a response.
+pub fn parse_result<T: DeserializeOwned>(response: &serde_json::Value) -> anyhow::Result<T> {
+    let result = response
+        .get("result")
+        .context("API error: expected `result` key")?;
+    T::deserialize(result).context("API error: unexpected `result`")
+}
+
+/// Like [`parse_result`], for results that older firmware wraps in a single
+/// element array.
+pub fn parse_result_object<T: DeserializeOwned>(response: &serde_json::Value) -> anyhow::Result<T> {
+    let mut result = response
+        .get("result")
+        .context("API error: expected `result` key")?;
+    if let Some(array) = result.as_array() {
+        result = array.first().context("API error: `result` is empty")?;
+    }
+    T::deserialize(result).context("API error: unexpected `result`")
+}
+
+/// Deserializes a response that carries its data outside of `result`.
+pub fn parse_response<T: DeserializeOwned>(response: &serde_json::Value) -> anyhow::Result<T> {
+    T::deserialize(response).context("API error: unexpected response")
+}
+
+/// Result of `get power`, keyed by `node1` to `node4`.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct PowerStatus(BTreeMap<String, String>);
+
+impl PowerStatus {
+    /// Returns the power state of each node, keyed by the name the BMC uses.
+    pub fn nodes(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.0
+            .iter()
+            .map(|(key, state)| (key.as_str(), state == "1"))
+    }
+
+    /// Returns whether node 1 to 4 is powered on, `None` if the BMC did not
+    /// report it.
+    pub fn is_on(&self, node: u8) -> Option<bool> {
+        self.0.get(&format!("node{node}")).map(|state| state == "1")
+    }
+}
+
+/// Result of `get usb`.
+#[derive(Debug, Deserialize)]
+pub struct UsbStatus {
+    pub node: String,
+    pub mode: String,
+    pub route: String,
+}
+
+/// Result of `get other`: firmware and network details of the BMC.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct Info(pub BTreeMap<String, String>);
+
+/// Result of `get cooling`, or the confirmation of `set cooling`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CoolingStatus {
+    Devices(Vec<CoolingDevice>),
+    Message(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CoolingDevice {
+    pub device: String,
+    pub speed: u64,
+    pub max_speed: u64,
+}
+
+/// Result of `get sdcard`.
+#[derive(Debug, Deserialize)]
+pub struct SdcardStatus {
+    pub free: u64,
+}
+
+/// Response of `get uart`.
+#[derive(Debug, Deserialize)]
+pub struct UartOutput {
+    pub uart: String,
+}
+
+/// Response to the start of an upload, identifying the transfer.
+#[derive(Debug, Deserialize)]
+pub struct FlashHandle {
+    pub handle: u64,
+}
+
+/// Response of `get flash` while a node is being flashed.
+#[derive(Debug, Deserialize)]
+pub enum FlashProgress {
+    Transferring {
+        id: u64,
+        size: u64,
+        #[serde(default)]
+        bytes_written: u64,
+    },
+    Done(serde_json::Value),
+    Error(serde_json::Value),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn result_object_is_unwrapped_from_an_array() {
+        let response = json!({ "result": [{ "node1": "1", "node2": "0" }] });
+        let status: PowerStatus = parse_result_object(&response).unwrap();
+        assert_eq!(status.is_on(1), Some(true));
+        assert_eq!(status.is_on(2), Some(false));
+        assert_eq!(status.is_on(3), None);
+    }
+
+    #[test]
+    fn result_object_is_taken_as_is() {
+        let response = json!({ "result": { "node": "Node 1", "mode": "Host", "route": "BMC" } });
+        let status: UsbStatus = parse_result_object(&response).unwrap();
+        assert_eq!(status.node, "Node 1");
+        assert_eq!(status.mode, "Host");
+        assert_eq!(status.route, "BMC");
+    }
+
+    #[test]
+    fn empty_result_array_is_an_error() {
+        let response = json!({ "result": [] });
+        let err = parse_result_object::<Info>(&response).unwrap_err();
+        assert_eq!(err.to_string(), "API error: `result` is empty");
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cli::ProgressBarStyle;
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use std::fmt::Write;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Spinner frames that render on any terminal.
+const ASCII_TICKS: &str = "|/-\\ ";
+
+pub fn build_progress_bar(size: u64, style: ProgressBarStyle) -> ProgressBar {
+    let template = match style {
+        ProgressBarStyle::Full => {
+            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.blue/blue}] {bytes}/{total_bytes} ({eta})"
+        }
+        ProgressBarStyle::Minimal => "[{bar:40}] {percent:>3}%",
+        ProgressBarStyle::Ascii => {
+            "{spinner} [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({eta})"
+        }
+    };
+
+    let mut progress_style = ProgressStyle::with_template(template)
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
+            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap();
+        })
+        .progress_chars("#>-");
+
+    if style == ProgressBarStyle::Ascii {
+        progress_style = progress_style.tick_chars(ASCII_TICKS);
+    }
+
+    let pb = ProgressBar::new(size);
+    pb.set_style(progress_style);
+    pb
+}
+
+pub fn build_spinner(style: ProgressBarStyle) -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.enable_steady_tick(Duration::from_millis(120));
+
+    let progress_style = match style {
+        ProgressBarStyle::Full | ProgressBarStyle::Minimal => {
+            ProgressStyle::with_template("{spinner:.green} {msg}").unwrap()
+        }
+        ProgressBarStyle::Ascii => ProgressStyle::with_template("{spinner} {msg}")
+            .unwrap()
+            .tick_chars(ASCII_TICKS),
+    };
+    pb.set_style(progress_style);
+    pb
+}
+
+/// Returns a token that is cancelled on the first Ctrl-C, letting operations
+/// that address several nodes stop in a controlled way.
+pub fn cancel_on_ctrl_c() -> CancellationToken {
+    let token = CancellationToken::new();
+    let guard = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            guard.cancel();
+        }
+    });
+    token
+}